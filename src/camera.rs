@@ -1,12 +1,17 @@
 
 use sdl2::rect::Point;
 use sdl2::render::WindowCanvas;
-use sdl2::video::Window;
+use sdl2::video::{Window, FullscreenType};
+use sdl2::mouse::MouseUtil;
+use sdl2::EventPump;
 use std::f32::consts::PI;
 
 pub use crate::voxels::{World, Color, Ray};
 use crate::linalg::*;
+use crate::render::Frame;
 
+// how far a horizon locked camera can look up or down
+const MAX_PITCH: f32 = 89.0 * PI / 180.0;
 
 
 pub struct Screen {
@@ -14,6 +19,10 @@ pub struct Screen {
     pub height_pix: u32,
     pixel_size: u8,
     canvas: WindowCanvas,
+    events: EventPump,
+    mouse: MouseUtil,
+    // in relative mode, hidden and turning the camera
+    mouse_captured: bool,
 }
 
 impl Screen {
@@ -21,24 +30,74 @@ impl Screen {
         let video_subsystem = sdl_ctx.video()?;
         let window = video_subsystem.window(title, width_pix as u32 * pixel_size as u32, height_pix as u32 * pixel_size as u32)
             .position_centered()
+            .resizable()
             .build()
             .map_err(|e| e.to_string())?;
         let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
-        sdl_ctx.mouse().show_cursor(false);
         canvas.set_scale(pixel_size as f32, pixel_size as f32).unwrap();
 
-        Ok(Screen {
+        let mut screen = Screen {
             width_pix,
             height_pix,
             pixel_size,
             canvas,
-        })
+            events: sdl_ctx.event_pump()?,
+            mouse: sdl_ctx.mouse(),
+            mouse_captured: false,
+        };
+        screen.capture_mouse(true);
+        Ok(screen)
+    }
+
+    // the window size is in real pixels, frames are rendered in pixels of pixel_size
+    pub fn resize(&mut self, window_width: u32, window_height: u32) {
+        self.width_pix = (window_width / self.pixel_size as u32).max(1);
+        self.height_pix = (window_height / self.pixel_size as u32).max(1);
+    }
+
+    pub fn toggle_fullscreen(&mut self) -> Result<(), String> {
+        let window = self.canvas.window_mut();
+        let next = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        window.set_fullscreen(next)
+    }
+
+    // a captured mouse reports how far it moved instead of leaving the window
+    pub fn capture_mouse(&mut self, capture: bool) {
+        self.mouse_captured = capture;
+        self.mouse.set_relative_mouse_mode(capture);
+        self.mouse.show_cursor(!capture);
+    }
+
+    pub fn mouse_captured(&self) -> bool {
+        self.mouse_captured
     }
 
     pub fn show(&mut self) {
         self.canvas.present();
     }
 
+    pub fn get_window(&self) -> &Window {
+        self.canvas.window()
+    }
+
+    pub fn events(&mut self) -> &mut EventPump {
+        &mut self.events
+    }
+
+    // the frame has to be rendered at the screen's resolution
+    pub fn present(&mut self, frame: &Frame) {
+        for row in 0..frame.height {
+            for col in 0..frame.width {
+                self.canvas.set_draw_color(frame.pixel(col, row).sdl_format());
+                self.canvas.draw_point(Point::new(col as i32, row as i32)).unwrap();
+            }
+        }
+        self.show();
+    }
+
     pub fn get_screen_center_pix(&self) -> (i32, i32) {
         let x = self.width_pix as i32 * self.pixel_size as i32 / 2;
         let y = self.height_pix as i32 * self.pixel_size as i32 / 2;
@@ -64,45 +123,38 @@ impl Screen {
 }
 
 
+// Renders the world from a position and orientation at any resolution,
+// the fov is the horizontal one and the vertical one follows from the aspect ratio
 pub struct Camera {
-    pub screen: Screen,
+    // how many pixels of mouse movement turn the camera by a radian
     pixels_per_unit: u32,
-    width_units: f32,
-    height_units: f32,
     world: Box<World>,
     position: Vec3,
-    lookat_direction: Vec3,
-    up_vector: Vec3,
+    // turns the camera's own axes, z forward and y up, into world space
+    orientation: Quat,
+    // no roll, and yaw turns around the world's up axis instead of the camera's
+    horizon_locked: bool,
     fov: f32,
-    focal_length: f32,
 }
 
 impl Camera {
-    pub fn new(screen: Screen, world: Box<World>, position: Vec3, direction: Vec3, up_vector: Vec3, fov: f32, pixels_per_unit: u32) -> Self {
-        let pixels_per_unit = pixels_per_unit;
-        let width_units = screen.width_pix as f32 / pixels_per_unit as f32;
-        let height_units = screen.height_pix as f32 / pixels_per_unit as f32;
-        let front_direction = direction.normalize();
-        let up_vector = up_vector.normalize();
-        let mut camera = Camera {
-            screen,
+    pub fn new(world: Box<World>, position: Vec3, direction: Vec3, up_vector: Vec3, fov: f32, pixels_per_unit: u32) -> Self {
+        Camera {
             pixels_per_unit,
-            width_units,
-            height_units,
             world,
             position,
-            lookat_direction: front_direction,
-            up_vector,
-            fov: 0.0,
-            focal_length: 0.0,
-        };
-        camera.set_fov(fov);
-        camera
+            orientation: Quat::look_rotation(&direction, &up_vector),
+            horizon_locked: false,
+            fov,
+        }
     }
 
     pub fn set_fov(&mut self, fov: f32) {
         self.fov = fov;
-        self.focal_length = (self.width_units / 2.0) / (fov / 2.0).tan();
+    }
+
+    pub fn fov(&self) -> f32 {
+        self.fov
     }
 
     pub fn zoom(&mut self, zoom: f32) {
@@ -110,68 +162,182 @@ impl Camera {
         self.set_fov(fov);
     }
 
-    pub fn get_window(&self) -> &Window {
-        self.screen.canvas.window()
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.world
     }
 
     pub fn get_direction(&self) -> Vec3 {
-        self.lookat_direction
+        self.forward()
     }
 
-    pub fn rotate_yaw(&mut self, angle: f32) {
-        let yaw = angle / self.pixels_per_unit as f32;
-        self.lookat_direction = self.lookat_direction.rotate_xz(-yaw);
-        self.up_vector = self.up_vector.rotate_xz(-yaw);
+    pub fn forward(&self) -> Vec3 {
+        self.orientation.rotate(&Z_AXIS)
     }
 
-    pub fn rotate_pitch(&mut self, angle: f32) {
-        let pitch = angle / self.pixels_per_unit as f32;
-        let right_vec = self.lookat_direction.cross(&self.up_vector);
-        self.lookat_direction = self.lookat_direction.rotate_around(&right_vec, pitch);
-        self.up_vector = self.up_vector.rotate_around(&right_vec, pitch);
+    pub fn up(&self) -> Vec3 {
+        self.orientation.rotate(&Y_AXIS)
     }
 
-    pub fn rotate_roll(&mut self, angle: f32) {
-        self.up_vector = self.up_vector.rotate_around(&self.lookat_direction, angle);
+    pub fn right(&self) -> Vec3 {
+        self.forward().cross(&self.up())
     }
 
-    pub fn move_rel_to_facing(&mut self, direction: Vec3) {
-        let up_component = Vec3::new(0.0, direction.y, 0.0);
-        let hori_component = direction.sub(&up_component);
+    pub fn orientation(&self) -> Quat {
+        self.orientation
+    }
+
+    pub fn set_orientation(&mut self, orientation: Quat) {
+        self.orientation = orientation.normalize();
+    }
 
-        let sign = (((self.lookat_direction.x >= 0.0) as i32) * 2) - 1;
-        let reference = Vec3::new(self.lookat_direction.x, 0.0, self.lookat_direction.z);
-        let angle = sign as f32 * Z_AXIS.angle(&reference);
+    pub fn is_horizon_locked(&self) -> bool {
+        self.horizon_locked
+    }
 
-        let hori_dir = hori_component.rotate_xz(angle);
-        let mov_dir = hori_dir.add(&up_component);
+    // locking levels the camera, keeping where it looks but within the pitch limit
+    pub fn set_horizon_locked(&mut self, locked: bool) {
+        self.horizon_locked = locked;
+        if locked {
+            let forward = self.forward();
+            let flat = Vec3::new(forward.x, 0.0, forward.z);
+            let flat = if flat.null() { self.up().scale(-forward.y.signum()) } else { flat };
+            self.orientation = Quat::look_rotation(&flat, &Y_AXIS);
+            self.rotate_pitch_radians(forward.y.clamp(-1.0, 1.0).asin());
+        }
+    }
 
-        self.position = self.position.add(&mov_dir);
+    pub fn rotate_yaw(&mut self, angle: f32) {
+        let yaw = Quat::from_axis_angle(&Y_AXIS, -angle / self.pixels_per_unit as f32);
+        self.orientation = if self.horizon_locked {
+            yaw.mul(&self.orientation)
+        }
+        else {
+            self.orientation.mul(&yaw)
+        }.normalize();
+    }
+
+    pub fn rotate_pitch(&mut self, angle: f32) {
+        self.rotate_pitch_radians(angle / self.pixels_per_unit as f32);
+    }
+
+    // never past straight up or down, locked or not, where the view would flip over
+    fn rotate_pitch_radians(&mut self, pitch: f32) {
+        let current = self.forward().y.clamp(-1.0, 1.0).asin();
+        let pitch = (current + pitch).clamp(-MAX_PITCH, MAX_PITCH) - current;
+        // the camera's right is its -x axis, positive pitch looks up
+        let rotation = Quat::from_axis_angle(&X_AXIS.scale(-1.0), pitch);
+        self.orientation = self.orientation.mul(&rotation).normalize();
+    }
+
+    pub fn rotate_roll(&mut self, angle: f32) {
+        if self.horizon_locked {
+            return;
+        }
+        let rotation = Quat::from_axis_angle(&Z_AXIS, angle);
+        self.orientation = self.orientation.mul(&rotation).normalize();
+    }
+
+    pub fn position(&self) -> Vec3 {
+        self.position
+    }
 
-        if mov_dir.x != 0.0 || mov_dir.y != 0.0 || mov_dir.z != 0.0 {
+    pub fn set_position(&mut self, position: Vec3) {
+        if !position.fp_equals(&self.position) {
+            self.position = position;
             self.world.update_chunks_in_area(self.position);
         }
     }
 
+    // turns a direction relative to where the camera faces, x left, y up and z forward,
+    // into world space. Only the horizontal part turns with the camera
+    pub fn facing_to_world(&self, direction: Vec3) -> Vec3 {
+        let forward = self.forward();
+        let mut flat = Vec3::new(forward.x, 0.0, forward.z).normalize();
+        if flat.null() {
+            // looking straight up or down, the top of the screen is where forward would be
+            let up = self.up().scale(-forward.y.signum());
+            flat = Vec3::new(up.x, 0.0, up.z).normalize();
+        }
+        let left = Vec3::new(flat.z, 0.0, -flat.x);
+        left.scale(direction.x).add(&Y_AXIS.scale(direction.y)).add(&flat.scale(direction.z))
+    }
+
+    pub fn move_rel_to_facing(&mut self, direction: Vec3) {
+        let mov_dir = self.facing_to_world(direction);
+        self.set_position(self.position.add(&mov_dir));
+    }
+
     pub fn world_to_camera(&self, p: Vec3) -> Vec3 {
         p.sub(&self.position)
     }
 
-    pub fn draw_frame(&mut self) {
-        let up_vec = self.up_vector.scale(self.height_units / self.screen.height_pix as f32);
-        let right_vec = self.lookat_direction.cross(&self.up_vector).scale(self.width_units / self.screen.width_pix as f32);
-        let front_vec = self.lookat_direction.scale(self.focal_length);
-
-        for x in (-(self.screen.width_pix as i32) / 2)..(self.screen.width_pix as i32 / 2) {
-            for y in (-(self.screen.height_pix as i32) / 2)..(self.screen.height_pix as i32 / 2) {
-                let v = front_vec.add(&right_vec.scale(x as f32)).add(&up_vec.scale(y as f32));
-                let ray = Ray::new(self.position, v);
-                let color = ray.trace(&self.world, 0);
-                self.screen.draw_pixel(x, y, color);
+    // samples rays per pixel are spread over the pixel and averaged
+    pub fn render(&self, width: u32, height: u32, samples: u32) -> Frame {
+        let samples = samples.max(1);
+        let focal_length = (width as f32 / 2.0) / (self.fov / 2.0).tan();
+        let (up, right) = (self.up(), self.right());
+        let front = self.forward().scale(focal_length);
+        let offsets: Vec<(f32, f32)> = (0..samples).map(|s| {
+            // every sample in its own column of the pixel, and rows spread by the golden ratio
+            ((s as f32 + 0.5) / samples as f32, (0.5 + s as f32 * 0.618_034).fract())
+        }).collect();
+
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for row in 0..height {
+            for col in 0..width {
+                let mut color = Color::new(0, 0, 0, 0);
+                for (dx, dy) in &offsets {
+                    let x = col as f32 + dx - width as f32 / 2.0;
+                    let y = height as f32 / 2.0 - row as f32 - dy;
+                    let v = front.add(&right.scale(x)).add(&up.scale(y));
+                    color = color.add(&Ray::new(self.position, v).trace(&self.world, 0));
+                }
+                pixels.push(color.scale(1.0 / samples as f32));
             }
         }
-        self.screen.show();
+        Frame {width, height, pixels}
     }
+
 }
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxels::WorldParams;
+
+    fn camera() -> Camera {
+        let world = World::new(WorldParams::default(), 0);
+        Camera::new(world, Vec3::new(0.0, 0.0, 0.0), Z_AXIS, Y_AXIS, PI / 2.0, 100)
+    }
+
+    #[test]
+    fn rolls_unless_the_horizon_is_locked() {
+        let mut camera = camera();
+        assert!(!camera.is_horizon_locked());
+        // the pitch limit holds while unlocked too, in small steps and in one large one
+        for _ in 0..10 {
+            camera.rotate_pitch(50.0);
+        }
+        assert!(camera.forward().y.asin() <= MAX_PITCH + 1e-4);
+        assert!(camera.forward().z > 0.0);
+        camera.rotate_pitch(-1000.0);
+        assert!(camera.forward().y.asin() >= -MAX_PITCH - 1e-4);
+        assert!(camera.forward().z > 0.0);
+        camera.rotate_pitch(camera.forward().y.asin() * -100.0);
+
+        camera.rotate_roll(0.3);
+        assert!(!camera.up().fp_equals(&Y_AXIS));
+
+        camera.set_horizon_locked(true);
+        assert!(camera.up().fp_equals(&Y_AXIS));
+        camera.rotate_roll(0.3);
+        assert!(camera.up().fp_equals(&Y_AXIS));
+    }
+}
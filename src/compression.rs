@@ -0,0 +1,304 @@
+use crate::voxels::MaterialID;
+
+
+
+// Chunks use the smallest of three representations for their voxels:
+//   Uniform, every voxel is the same material
+//   Palette, up to MAX_PALETTE materials with bit packed indices into them
+//   Dense, one byte per voxel
+const MAX_PALETTE: usize = 16;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageKind {
+    Uniform,
+    Palette,
+    Dense,
+}
+
+
+#[derive(Clone, Debug)]
+pub enum VoxelStorage {
+    Uniform(MaterialID),
+    Palette {
+        palette: Vec<MaterialID>,
+        bits: usize,
+        data: Vec<u64>,
+    },
+    Dense(Vec<MaterialID>),
+}
+
+impl VoxelStorage {
+    pub fn new(material: MaterialID) -> VoxelStorage {
+        VoxelStorage::Uniform(material)
+    }
+
+    pub fn kind(&self) -> StorageKind {
+        match self {
+            VoxelStorage::Uniform(_) => StorageKind::Uniform,
+            VoxelStorage::Palette {..} => StorageKind::Palette,
+            VoxelStorage::Dense(_) => StorageKind::Dense,
+        }
+    }
+
+    // bytes allocated on the heap for the voxels
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            VoxelStorage::Uniform(_) => 0,
+            VoxelStorage::Palette {palette, data, ..} => palette.len() + data.len() * std::mem::size_of::<u64>(),
+            VoxelStorage::Dense(voxels) => voxels.len(),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> MaterialID {
+        match self {
+            VoxelStorage::Uniform(material) => *material,
+            VoxelStorage::Palette {palette, bits, data} => palette[read_packed(data, *bits, index)],
+            VoxelStorage::Dense(voxels) => voxels[index],
+        }
+    }
+
+    pub fn set(&mut self, index: usize, material: MaterialID, volume: usize) {
+        match self {
+            VoxelStorage::Uniform(current) => {
+                if *current == material {
+                    return;
+                }
+                let mut data = vec![0; packed_words(1, volume)];
+                write_packed(&mut data, 1, index, 1);
+                *self = VoxelStorage::Palette {palette: vec![*current, material], bits: 1, data};
+            },
+
+            VoxelStorage::Palette {palette, bits, data} => {
+                let entry = match palette.iter().position(|&m| m == material) {
+                    Some(entry) => entry,
+                    None if palette.len() < MAX_PALETTE => {
+                        palette.push(material);
+                        if palette.len() > 1 << *bits {
+                            *data = repack(data, *bits, *bits * 2, volume);
+                            *bits *= 2;
+                        }
+                        palette.len() - 1
+                    },
+                    None => {
+                        let mut voxels: Vec<MaterialID> = (0..volume).map(|i| self.get(i)).collect();
+                        voxels[index] = material;
+                        *self = VoxelStorage::Dense(voxels);
+                        return;
+                    },
+                };
+                write_packed(data, *bits, index, entry);
+            },
+
+            VoxelStorage::Dense(voxels) => voxels[index] = material,
+        }
+    }
+
+    // picks the smallest representation for the voxels currently stored.
+    // Palettes only grow while editing, so this is also where unused entries get dropped
+    pub fn compact(&mut self, volume: usize) {
+        let mut used: Vec<MaterialID> = Vec::new();
+        for i in 0..volume {
+            let material = self.get(i);
+            if !used.contains(&material) {
+                used.push(material);
+                if used.len() > MAX_PALETTE {
+                    break;
+                }
+            }
+        }
+
+        *self = match used.len() {
+            1 => VoxelStorage::Uniform(used[0]),
+            n if n <= MAX_PALETTE => {
+                let bits = palette_bits(n);
+                let mut data = vec![0; packed_words(bits, volume)];
+                for i in 0..volume {
+                    let material = self.get(i);
+                    let entry = used.iter().position(|&m| m == material).unwrap();
+                    write_packed(&mut data, bits, i, entry);
+                }
+                VoxelStorage::Palette {palette: used, bits, data}
+            },
+            _ => match self {
+                VoxelStorage::Dense(_) => return,
+                _ => VoxelStorage::Dense((0..volume).map(|i| self.get(i)).collect()),
+            },
+        };
+    }
+
+    pub fn to_vec(&self, volume: usize) -> Vec<MaterialID> {
+        (0..volume).map(|i| self.get(i)).collect()
+    }
+}
+
+
+// indices are packed in widths that divide 64, so none of them straddles two words
+fn palette_bits(entries: usize) -> usize {
+    match entries {
+        0..=2 => 1,
+        3..=4 => 2,
+        _ => 4,
+    }
+}
+
+fn packed_words(bits: usize, volume: usize) -> usize {
+    (volume * bits).div_ceil(64)
+}
+
+fn read_packed(data: &[u64], bits: usize, index: usize) -> usize {
+    let bit = index * bits;
+    let mask = (1u64 << bits) - 1;
+    ((data[bit / 64] >> (bit % 64)) & mask) as usize
+}
+
+fn write_packed(data: &mut [u64], bits: usize, index: usize, value: usize) {
+    let bit = index * bits;
+    let mask = ((1u64 << bits) - 1) << (bit % 64);
+    data[bit / 64] = (data[bit / 64] & !mask) | ((value as u64) << (bit % 64));
+}
+
+fn repack(data: &[u64], bits: usize, new_bits: usize, volume: usize) -> Vec<u64> {
+    let mut new_data = vec![0; packed_words(new_bits, volume)];
+    for i in 0..volume {
+        write_packed(&mut new_data, new_bits, i, read_packed(data, bits, i));
+    }
+    new_data
+}
+
+
+// Run length encoding used for chunks on disk: pairs of (run length u16 little endian, material)
+pub fn rle_encode(voxels: &[MaterialID]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < voxels.len() {
+        let material = voxels[i];
+        let mut run = 1;
+        while i + run < voxels.len() && voxels[i + run] == material && run < u16::MAX as usize {
+            run += 1;
+        }
+        out.extend_from_slice(&(run as u16).to_le_bytes());
+        out.push(material);
+        i += run;
+    }
+    out
+}
+
+pub fn rle_decode(bytes: &[u8], volume: usize) -> Result<Vec<MaterialID>, String> {
+    if !bytes.len().is_multiple_of(3) {
+        return Err(format!("run length data of {} bytes is not made of whole runs", bytes.len()));
+    }
+    let mut voxels = Vec::with_capacity(volume);
+    for run in bytes.chunks(3) {
+        let length = u16::from_le_bytes([run[0], run[1]]) as usize;
+        if voxels.len() + length > volume {
+            return Err(format!("run length data decodes to more than {} voxels", volume));
+        }
+        voxels.extend(std::iter::repeat_n(run[2], length));
+    }
+    if voxels.len() != volume {
+        return Err(format!("run length data decodes to {} voxels, expected {}", voxels.len(), volume));
+    }
+    Ok(voxels)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VOLUME: usize = 512;
+
+    // a voxel pattern using the materials 0..n, kept next to the storage to compare against
+    fn fill(storage: &mut VoxelStorage, expected: &mut [MaterialID], n: usize) {
+        for (i, slot) in expected.iter_mut().enumerate() {
+            let material = ((i * 7) % n) as MaterialID;
+            storage.set(i, material, VOLUME);
+            *slot = material;
+        }
+    }
+
+    fn assert_holds(storage: &VoxelStorage, expected: &[MaterialID]) {
+        assert_eq!(storage.to_vec(VOLUME), expected);
+    }
+
+    #[test]
+    fn uniform_becomes_a_palette_on_the_first_other_material() {
+        let mut storage = VoxelStorage::new(3);
+        storage.set(10, 3, VOLUME);
+        assert_eq!(storage.kind(), StorageKind::Uniform);
+        storage.set(10, 5, VOLUME);
+        assert_eq!(storage.kind(), StorageKind::Palette);
+        let mut expected = vec![3; VOLUME];
+        expected[10] = 5;
+        assert_holds(&storage, &expected);
+    }
+
+    #[test]
+    fn palette_repacks_as_it_grows() {
+        let mut storage = VoxelStorage::new(0);
+        let mut expected = vec![0; VOLUME];
+        // crossing 2, 4 and up to 16 entries, so the indices go from 1 to 2 to 4 bits
+        for n in [2, 3, 4, 5, 16] {
+            fill(&mut storage, &mut expected, n);
+            assert_eq!(storage.kind(), StorageKind::Palette, "{} materials", n);
+            assert_holds(&storage, &expected);
+            let VoxelStorage::Palette {bits, ..} = &storage else { unreachable!() };
+            assert_eq!(*bits, palette_bits(n));
+        }
+    }
+
+    #[test]
+    fn too_many_materials_become_dense() {
+        let mut storage = VoxelStorage::new(0);
+        let mut expected = vec![0; VOLUME];
+        fill(&mut storage, &mut expected, MAX_PALETTE + 1);
+        assert_eq!(storage.kind(), StorageKind::Dense);
+        assert_holds(&storage, &expected);
+        storage.set(3, 200, VOLUME);
+        expected[3] = 200;
+        assert_holds(&storage, &expected);
+    }
+
+    #[test]
+    fn compact_goes_back_to_the_smallest_kind() {
+        let mut storage = VoxelStorage::new(0);
+        let mut expected = vec![0; VOLUME];
+        fill(&mut storage, &mut expected, 40);
+        assert_eq!(storage.kind(), StorageKind::Dense);
+
+        // down to three materials
+        for (i, slot) in expected.iter_mut().enumerate() {
+            let material = (i % 3) as MaterialID;
+            storage.set(i, material, VOLUME);
+            *slot = material;
+        }
+        storage.compact(VOLUME);
+        assert_eq!(storage.kind(), StorageKind::Palette);
+        assert_holds(&storage, &expected);
+        let VoxelStorage::Palette {palette, bits, ..} = &storage else { unreachable!() };
+        assert_eq!((palette.len(), *bits), (3, 2));
+
+        storage.set(7, 2, VOLUME);
+        expected[7] = 2;
+        assert_holds(&storage, &expected);
+
+        for i in 0..VOLUME {
+            storage.set(i, 9, VOLUME);
+        }
+        storage.compact(VOLUME);
+        assert_eq!(storage.kind(), StorageKind::Uniform);
+        assert_holds(&storage, &[9; VOLUME]);
+        assert_eq!(storage.memory_usage(), 0);
+    }
+
+    #[test]
+    fn run_length_encoding_round_trips() {
+        let mut voxels = vec![1; 70_000];
+        voxels[5] = 2;
+        voxels[69_999] = 3;
+        assert_eq!(rle_decode(&rle_encode(&voxels), voxels.len()).unwrap(), voxels);
+        assert!(rle_decode(&rle_encode(&voxels), voxels.len() - 1).is_err());
+        assert!(rle_decode(&[1, 0], 1).is_err());
+    }
+}
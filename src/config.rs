@@ -0,0 +1,336 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::linalg::*;
+use crate::voxels::WorldParams;
+use crate::input::{Action, InputMap, parse_bindings};
+
+
+
+// Every setting of the program. They start at their defaults, then the config file is applied
+// and then the command line, so the command line wins. The config file has one setting per line:
+//   chunk_size = 16
+//   # comments start with a hash
+//   bind_jump = Space, MouseX1
+// and every setting can also be given on the command line as --chunk-size 16
+pub const DEFAULT_CONFIG_FILE: &str = "raytracer.cfg";
+// options without a value, only on the command line
+const FLAGS: [&str; 3] = ["bench", "play-path", "headless"];
+// settings that change what the same input does, input recordings keep them so replays don't depend on the config
+pub const RECORDED_SETTINGS: [&str; 9] = [
+    "chunk_size", "render_distance", "camera_speed", "walk_speed",
+    "mouse_sensitivity", "scroll_sensitivity", "zoom_sensitivity", "horizon_lock", "pixels_per_unit",
+];
+
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    // world
+    pub chunk_size: usize,
+    pub render_distance: usize,
+    pub memory_budget_mb: usize,
+    pub seed: u64,
+    pub save_dir: PathBuf,
+    pub world: Option<PathBuf>,
+
+    // window
+    pub screen_width: u32,
+    pub screen_height: u32,
+    pub pixel_size: u8,
+    pub pixels_per_unit: u32,
+    pub fps: f32,
+
+    // camera, without a position it starts above the terrain at the origin
+    pub fov: f32,
+    pub camera_position: Option<Vec3>,
+    pub camera_direction: Vec3,
+    pub camera_up: Vec3,
+    // no roll and a limited pitch, toggled with toggle_horizon_lock
+    pub horizon_lock: bool,
+
+    // controls
+    pub mouse_sensitivity: f32,
+    pub scroll_sensitivity: f32,
+    pub zoom_sensitivity: f32,
+    pub camera_speed: f32,
+    pub walk_speed: f32,
+    pub bindings: InputMap,
+
+    // importing and exporting
+    pub import_vox: Option<PathBuf>,
+    pub vox_at: Pos,
+    pub export_vox: Option<PathBuf>,
+    pub export_mesh: Option<PathBuf>,
+    pub export_region: Option<(Pos, Pos)>,
+
+    // camera paths and rendering them
+    pub path: Option<PathBuf>,
+    pub path_speed: f32,
+    pub render: Option<PathBuf>,
+    pub render_size: (u32, u32),
+    pub render_samples: u32,
+    pub render_fps: u32,
+
+    // recording and replaying input
+    pub record_input: Option<PathBuf>,
+    pub replay_input: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let params = WorldParams::default();
+        Config {
+            chunk_size: params.chunk_size,
+            render_distance: params.render_distance,
+            memory_budget_mb: params.memory_budget / (1024 * 1024),
+            seed: 0,
+            save_dir: PathBuf::from("saves"),
+            world: None,
+
+            screen_width: 320,
+            screen_height: 180,
+            pixel_size: 2,
+            pixels_per_unit: 100,
+            fps: 3.0,
+
+            // the view the camera always had: its fov used to be 90 taken as radians, which the
+            // tangent of the projection turns into 116.6 degrees
+            fov: 116.6,
+            camera_position: None,
+            camera_direction: Vec3::new(0.0, 0.0, 1.0),
+            camera_up: Vec3::new(0.0, 1.0, 0.0),
+            horizon_lock: false,
+
+            mouse_sensitivity: 0.2,
+            scroll_sensitivity: 0.1,
+            zoom_sensitivity: 0.1,
+            camera_speed: 10.0,
+            walk_speed: 4.5,
+            bindings: InputMap::default(),
+
+            import_vox: None,
+            vox_at: Pos::new(0, 0, 0),
+            export_vox: None,
+            export_mesh: None,
+            export_region: None,
+
+            path: None,
+            path_speed: 5.0,
+            render: None,
+            render_size: (1280, 720),
+            render_samples: 4,
+            render_fps: 30,
+
+            record_input: None,
+            replay_input: None,
+        }
+    }
+}
+
+impl Config {
+    // the file given with --config, or the default one when it exists
+    pub fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+        match args.iter().position(|arg| arg == "--config") {
+            Some(i) => {
+                let file = args.get(i + 1).ok_or("missing value for --config")?;
+                config.load(Path::new(file))?;
+            },
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => config.load(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => {},
+        }
+
+        let mut i = 1;
+        while i < args.len() {
+            let name = args[i].strip_prefix("--").ok_or(format!("unexpected argument {:?}", args[i]))?;
+            if FLAGS.contains(&name) {
+                i += 1;
+                continue;
+            }
+            let value = args.get(i + 1).ok_or(format!("missing value for --{}", name))?;
+            if name != "config" {
+                config.set(&name.replace('-', "_"), value).map_err(|e| format!("--{}: {}", name, e))?;
+            }
+            i += 2;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=')
+                .ok_or(format!("{} line {}: expected name = value, found {:?}", path.display(), n + 1, line))?;
+            self.set(key.trim(), value.trim()).map_err(|e| format!("{} line {}: {}", path.display(), n + 1, e))?;
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "chunk_size" => self.chunk_size = parse(value)?,
+            "render_distance" => self.render_distance = parse(value)?,
+            "memory_budget_mb" => self.memory_budget_mb = parse(value)?,
+            "seed" => self.seed = parse(value)?,
+            "save_dir" => self.save_dir = PathBuf::from(value),
+            "world" => self.world = Some(PathBuf::from(value)),
+
+            "screen_width" => self.screen_width = positive(value)?,
+            "screen_height" => self.screen_height = positive(value)?,
+            "pixel_size" => self.pixel_size = positive(value)?,
+            "pixels_per_unit" => self.pixels_per_unit = positive(value)?,
+            "fps" => self.fps = positive(value)?,
+
+            "fov" => {
+                self.fov = parse(value)?;
+                if !(1.0..180.0).contains(&self.fov) {
+                    return Err(format!("the fov is in degrees and has to be between 1 and 180, got {}", value));
+                }
+            },
+            "camera_position" => self.camera_position = Some(parse_vec3(value)?),
+            "camera_direction" => self.camera_direction = parse_vec3(value)?,
+            "camera_up" => self.camera_up = parse_vec3(value)?,
+            "horizon_lock" => self.horizon_lock = parse(value)?,
+
+            "mouse_sensitivity" => self.mouse_sensitivity = parse(value)?,
+            "scroll_sensitivity" => self.scroll_sensitivity = parse(value)?,
+            "zoom_sensitivity" => self.zoom_sensitivity = parse(value)?,
+            "camera_speed" => self.camera_speed = positive(value)?,
+            "walk_speed" => self.walk_speed = positive(value)?,
+
+            "import_vox" => self.import_vox = Some(PathBuf::from(value)),
+            "vox_at" => self.vox_at = parse_pos(value)?,
+            "export_vox" => self.export_vox = Some(PathBuf::from(value)),
+            "export_mesh" => self.export_mesh = Some(PathBuf::from(value)),
+            "export_region" => {
+                let (min, max) = value.split_once(':').ok_or(format!("invalid region {:?}, expected min:max", value))?;
+                self.export_region = Some((parse_pos(min)?, parse_pos(max)?));
+            },
+
+            "path" => self.path = Some(PathBuf::from(value)),
+            "path_speed" => self.path_speed = positive(value)?,
+            "render" => self.render = Some(PathBuf::from(value)),
+            "render_size" => self.render_size = parse_size(value)?,
+            "render_samples" => self.render_samples = positive(value)?,
+            "render_fps" => self.render_fps = positive(value)?,
+
+            "record_input" => self.record_input = Some(PathBuf::from(value)),
+            "replay_input" => self.replay_input = Some(PathBuf::from(value)),
+
+            _ => match key.strip_prefix("bind_").and_then(Action::from_name) {
+                Some(action) => self.bindings.bind(action, parse_bindings(value)?),
+                None => return Err(format!("unknown setting {:?}", key)),
+            },
+        }
+        Ok(())
+    }
+
+    // checks the settings that only make sense together
+    pub fn validate(&self) -> Result<(), String> {
+        self.world_params()?;
+        self.bindings.check_conflicts()?;
+        if self.camera_direction.null() {
+            return Err("camera_direction can't be 0,0,0".to_string());
+        }
+        if self.camera_up.cross(&self.camera_direction).null() {
+            return Err("camera_up has to point away from camera_direction".to_string());
+        }
+        if self.export_region.is_none() && (self.export_vox.is_some() || self.export_mesh.is_some()) {
+            return Err("exporting needs an export_region min_x,min_y,min_z:max_x,max_y,max_z".to_string());
+        }
+        if self.record_input.is_some() && self.replay_input.is_some() {
+            return Err("can't record input while replaying it".to_string());
+        }
+        Ok(())
+    }
+
+    // the values of RECORDED_SETTINGS, as set reads them back
+    pub fn recorded_settings(&self) -> Vec<(String, String)> {
+        let values = [
+            self.chunk_size.to_string(), self.render_distance.to_string(), self.camera_speed.to_string(), self.walk_speed.to_string(),
+            self.mouse_sensitivity.to_string(), self.scroll_sensitivity.to_string(), self.zoom_sensitivity.to_string(),
+            self.horizon_lock.to_string(), self.pixels_per_unit.to_string(),
+        ];
+        RECORDED_SETTINGS.iter().map(|name| name.to_string()).zip(values).collect()
+    }
+
+    pub fn world_params(&self) -> Result<WorldParams, String> {
+        let memory_budget = self.memory_budget_mb.checked_mul(1024 * 1024)
+            .ok_or(format!("memory_budget_mb of {} is more memory than can be addressed", self.memory_budget_mb))?;
+        WorldParams::new(self.chunk_size, self.render_distance, memory_budget)
+    }
+}
+
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value {:?}", value))
+}
+
+fn positive<T: FromStr + PartialOrd + Default>(value: &str) -> Result<T, String> {
+    parse::<T>(value).ok().filter(|n| *n > T::default())
+        .ok_or(format!("invalid value {:?}, expected a positive number", value))
+}
+
+pub fn parse_pos(text: &str) -> Result<Pos, String> {
+    let parts: Vec<i32> = text.split(',').map(|n| n.trim().parse()).collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid position {:?}, expected x,y,z", text))?;
+    match parts[..] {
+        [x, y, z] => Ok(Pos::new(x, y, z)),
+        _ => Err(format!("invalid position {:?}, expected x,y,z", text)),
+    }
+}
+
+pub fn parse_vec3(text: &str) -> Result<Vec3, String> {
+    let parts: Vec<f32> = text.split(',').map(|n| n.trim().parse()).collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid vector {:?}, expected x,y,z", text))?;
+    match parts[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(format!("invalid vector {:?}, expected x,y,z", text)),
+    }
+}
+
+pub fn parse_size(text: &str) -> Result<(u32, u32), String> {
+    let (width, height) = text.split_once('x').ok_or(format!("invalid size {:?}, expected WIDTHxHEIGHT", text))?;
+    match (width.parse::<u32>(), height.parse::<u32>()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(format!("invalid size {:?}, expected WIDTHxHEIGHT", text)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("raytracer").chain(args.iter().copied()).map(String::from).collect()
+    }
+
+    #[test]
+    fn command_line_overrides_defaults() {
+        let config = Config::from_args(&args(&["--seed", "7", "--chunk-size", "16", "--bench"])).unwrap();
+        assert_eq!((config.seed, config.chunk_size), (7, 16));
+        assert_eq!(config.world_params().unwrap().chunk_size, 16);
+    }
+
+    #[test]
+    fn huge_memory_budget_is_an_error() {
+        let err = Config::from_args(&args(&["--memory-budget-mb", &usize::MAX.to_string()])).err().unwrap();
+        assert!(err.contains("memory_budget_mb"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_values_name_the_setting() {
+        let err = Config::from_args(&args(&["--fov", "200"])).err().unwrap();
+        assert!(err.starts_with("--fov"), "{}", err);
+        assert!(Config::from_args(&args(&["--screen-width", "0"])).is_err());
+        assert!(Config::from_args(&args(&["--no-such-setting", "1"])).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::linalg::*;
+use crate::voxels::{Chunk, MaterialID, Materials};
+use crate::structures;
+
+
+
+// Terrain generator. The surface height of every column comes from fractal gradient noise (fBm),
+// and a 3D density function on top of it bends the surface into overhangs, carves caves and
+// raises floating islands. Any voxel is a function of the seed and its world position only,
+// so chunks line up with their neighbours no matter in which order they are generated.
+// Random details use an rng derived from the seed and the chunk coordinates, so regenerating
+// a chunk always gives back the same voxels
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainGenerator {
+    seed: u64,
+    // water fills every empty voxel at or below this height
+    pub sea_level: i32,
+    pub base_height: f32,
+    // how far the surface goes above and below base_height
+    pub amplitude: f32,
+    // horizontal size of the largest hills, in voxels
+    pub scale: f32,
+    pub octaves: u32,
+    pub dirt_depth: i32,
+    // columns whose surface is at most this much above the sea are beach
+    pub beach_height: i32,
+    // loose stones lying on the grass of every chunk
+    pub stones_per_chunk: u32,
+
+    // how many voxels 3D noise moves the surface up or down, enough of it makes overhangs
+    pub overhang_amplitude: f32,
+    pub overhang_scale: f32,
+    // tunnels run where two noise fields are both within this distance of zero
+    pub tunnel_width: f32,
+    pub tunnel_scale: f32,
+    // large caverns open where their noise is above the threshold
+    pub cavern_threshold: f32,
+    pub cavern_scale: f32,
+    // caves don't come closer than this to the surface
+    pub cave_depth: i32,
+    // islands float in a band of island_thickness voxels around island_level
+    pub island_level: i32,
+    pub island_thickness: f32,
+    pub island_threshold: f32,
+    pub island_scale: f32,
+    pub ores: [Ore; 3],
+}
+
+// pockets of an ore replace stone deeper than min_depth below the surface
+#[derive(Clone, Copy, Debug)]
+pub struct Ore {
+    pub material: Materials,
+    pub min_depth: i32,
+    pub threshold: f32,
+    pub scale: f32,
+}
+
+// noise fields, so every feature gets its own independent noise
+const OVERHANG_FIELD: u64 = 1;
+const TUNNEL_FIELDS: (u64, u64) = (2, 3);
+const CAVERN_FIELD: u64 = 4;
+const ISLAND_FIELD: u64 = 5;
+const ORE_FIELD: u64 = 6;
+
+// rng streams
+const STONE_STREAM: u64 = 0;
+
+impl TerrainGenerator {
+    pub fn new(seed: u64) -> TerrainGenerator {
+        TerrainGenerator {
+            seed,
+            sea_level: -3,
+            base_height: -2.0,
+            amplitude: 8.0,
+            scale: 48.0,
+            octaves: 4,
+            dirt_depth: 3,
+            beach_height: 1,
+            stones_per_chunk: 2,
+
+            overhang_amplitude: 3.0,
+            overhang_scale: 12.0,
+            tunnel_width: 0.08,
+            tunnel_scale: 24.0,
+            cavern_threshold: 0.55,
+            cavern_scale: 32.0,
+            cave_depth: 4,
+            island_level: 16,
+            island_thickness: 6.0,
+            island_threshold: 0.35,
+            island_scale: 20.0,
+            ores: [
+                Ore {material: Materials::Coal, min_depth: 4, threshold: 0.55, scale: 5.0},
+                Ore {material: Materials::Iron, min_depth: 12, threshold: 0.6, scale: 4.0},
+                Ore {material: Materials::Gold, min_depth: 24, threshold: 0.65, scale: 3.0},
+            ],
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn height_at(&self, x: i32, z: i32) -> i32 {
+        let noise = self.fbm(x as f32 / self.scale, z as f32 / self.scale);
+        (self.base_height + self.amplitude * noise).floor() as i32
+    }
+
+    // the density function, height is height_at of the column
+    pub fn is_solid(&self, pos: Pos, height: i32) -> bool {
+        let depth = height - pos.y;
+        let density = depth as f32 + self.overhang_amplitude * self.noise3(pos, self.overhang_scale, OVERHANG_FIELD);
+        if density >= 0.0 {
+            return depth < self.cave_depth || !self.is_cave(pos);
+        }
+
+        let band = (pos.y - self.island_level) as f32 / self.island_thickness;
+        // islands thin out towards the edges of their band
+        band.abs() < 1.0 && self.noise3(pos, self.island_scale, ISLAND_FIELD) - band * band > self.island_threshold
+    }
+
+    fn is_cave(&self, pos: Pos) -> bool {
+        let tunnel = self.noise3(pos, self.tunnel_scale, TUNNEL_FIELDS.0).abs() < self.tunnel_width
+            && self.noise3(pos, self.tunnel_scale, TUNNEL_FIELDS.1).abs() < self.tunnel_width;
+        tunnel || self.noise3(pos, self.cavern_scale, CAVERN_FIELD) > self.cavern_threshold
+    }
+
+    // the first spot above the ground, or the sea, at x z with room for a camera
+    pub fn spawn_height(&self, x: i32, z: i32) -> i32 {
+        let height = self.height_at(x, z);
+        let mut y = height.max(self.sea_level) + 1;
+        while self.is_solid(Pos::new(x, y, z), height) || self.is_solid(Pos::new(x, y + 1, z), height) {
+            y += 1;
+        }
+        y
+    }
+
+    pub fn generate(&self, coords: Pos, size: usize) -> Box<Chunk> {
+        let mut chunk = Chunk::new(coords, size);
+        let origin = coords.scale(size as i32);
+        for x in 0..size {
+            for z in 0..size {
+                let column = self.generate_column(origin.x + x as i32, origin.z + z as i32, origin.y, size);
+                for (y, material) in column.into_iter().enumerate() {
+                    if material != Materials::Air as MaterialID {
+                        chunk.set_voxel(Vec3::new(x as f32, y as f32, z as f32), material);
+                    }
+                }
+            }
+        }
+
+        let mut rng = self.rng_at(coords, STONE_STREAM);
+        for _ in 0..self.stones_per_chunk {
+            let (x, z) = (rng.gen_range(0..size), rng.gen_range(0..size));
+            let top = (1..size).rev().find(|&y| {
+                chunk.get_voxel(Vec3::new(x as f32, (y - 1) as f32, z as f32)) == Materials::Grass as MaterialID
+                    && chunk.get_voxel(Vec3::new(x as f32, y as f32, z as f32)) == Materials::Air as MaterialID
+            });
+            if let Some(y) = top {
+                chunk.set_voxel(Vec3::new(x as f32, y as f32, z as f32), Materials::Stone as MaterialID);
+            }
+        }
+
+        structures::decorate(self, &mut chunk);
+        chunk.compact();
+        chunk
+    }
+
+    // the highest voxel of the ground at x z that isn't part of an island, and its material
+    pub fn surface_at(&self, x: i32, z: i32) -> (i32, MaterialID) {
+        let y = self.spawn_height(x, z) - 1;
+        (y, self.generate_column(x, z, y, 1)[0])
+    }
+
+    // materials of the voxels from bottom to bottom + size - 1 of a column.
+    // The soil layers need to know how much solid ground is above each voxel, so the density is
+    // also sampled up to dirt_depth voxels above the chunk
+    fn generate_column(&self, x: i32, z: i32, bottom: i32, size: usize) -> Vec<MaterialID> {
+        let height = self.height_at(x, z);
+        let top = bottom + size as i32 + self.dirt_depth;
+        // caves and ledges deep in the ground are bare rock, only the surface and islands get soil
+        let soil_from = height - self.overhang_amplitude.ceil() as i32 - self.dirt_depth;
+
+        let mut column = vec![Materials::Air as MaterialID; size];
+        let mut covered = 0;
+        for y in (bottom..top).rev() {
+            let pos = Pos::new(x, y, z);
+            let material = if !self.is_solid(pos, height) {
+                covered = 0;
+                // caves under the seabed stay dry unless they reach it
+                if y <= self.sea_level && y > soil_from { Materials::Water } else { Materials::Air }
+            }
+            else {
+                covered += 1;
+                let beach = y <= self.sea_level + self.beach_height;
+                if y > soil_from && covered == 1 {
+                    if beach { Materials::Sand } else { Materials::Grass }
+                }
+                else if y > soil_from && covered <= self.dirt_depth {
+                    if beach { Materials::Sand } else { Materials::Dirt }
+                }
+                else {
+                    self.rock_at(pos, height)
+                }
+            };
+            if y < bottom + size as i32 {
+                column[(y - bottom) as usize] = material as MaterialID;
+            }
+        }
+        column
+    }
+
+    fn rock_at(&self, pos: Pos, height: i32) -> Materials {
+        let depth = height - pos.y;
+        // deepest ores first, so they win where pockets overlap
+        for (i, ore) in self.ores.iter().enumerate().rev() {
+            if depth >= ore.min_depth && self.noise3(pos, ore.scale, ORE_FIELD + i as u64) > ore.threshold {
+                return ore.material;
+            }
+        }
+        Materials::Stone
+    }
+
+    // an rng that only depends on the seed, the coordinates and the stream, each user of random
+    // numbers takes its own stream so they don't repeat each other's choices
+    pub fn rng_at(&self, coords: Pos, stream: u64) -> StdRng {
+        let mut h = self.seed ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        for c in [coords.x, coords.y, coords.z] {
+            h = mix(h ^ c as u32 as u64);
+        }
+        StdRng::seed_from_u64(h)
+    }
+
+    // octaves of noise, each at twice the frequency and half the weight of the previous one.
+    // Normalized back to about -1..1
+    fn fbm(&self, x: f32, z: f32) -> f32 {
+        let mut total = 0.0;
+        let mut weight = 1.0;
+        let mut weights = 0.0;
+        let mut frequency = 1.0;
+        for octave in 0..self.octaves {
+            total += weight * self.noise(x * frequency, z * frequency, octave as u64);
+            weights += weight;
+            weight *= 0.5;
+            frequency *= 2.0;
+        }
+        total / weights
+    }
+
+    // 2D gradient noise: a pseudo random unit gradient on every lattice point, blended with a quintic fade
+    fn noise(&self, x: f32, z: f32, octave: u64) -> f32 {
+        let (x0, z0) = (x.floor(), z.floor());
+        let (fx, fz) = (x - x0, z - z0);
+        let (ix, iz) = (x0 as i32, z0 as i32);
+
+        let corner = |dx: i32, dz: i32| {
+            let angle = (self.hash(ix + dx, iz + dz, octave) >> 40) as f32 / (1u64 << 24) as f32 * std::f32::consts::TAU;
+            angle.cos() * (fx - dx as f32) + angle.sin() * (fz - dz as f32)
+        };
+
+        let (u, v) = (fade(fx), fade(fz));
+        let near = lerp(corner(0, 0), corner(1, 0), u);
+        let far = lerp(corner(0, 1), corner(1, 1), u);
+        // the raw range is -sqrt(0.5)..sqrt(0.5)
+        lerp(near, far, v) * std::f32::consts::SQRT_2
+    }
+
+    // 3D gradient noise sampled at a voxel, scale is the size of the lattice cells in voxels.
+    // Gradients are picked from the 12 edge directions of a cube, which keeps the result in about -1..1
+    fn noise3(&self, pos: Pos, scale: f32, field: u64) -> f32 {
+        const GRADIENTS: [(f32, f32, f32); 12] = [
+            (1.0, 1.0, 0.0), (-1.0, 1.0, 0.0), (1.0, -1.0, 0.0), (-1.0, -1.0, 0.0),
+            (1.0, 0.0, 1.0), (-1.0, 0.0, 1.0), (1.0, 0.0, -1.0), (-1.0, 0.0, -1.0),
+            (0.0, 1.0, 1.0), (0.0, -1.0, 1.0), (0.0, 1.0, -1.0), (0.0, -1.0, -1.0),
+        ];
+        let (x, y, z) = (pos.x as f32 / scale, pos.y as f32 / scale, pos.z as f32 / scale);
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (fx, fy, fz) = (x - x0, y - y0, z - z0);
+        let (ix, iy, iz) = (x0 as i32, y0 as i32, z0 as i32);
+
+        let corner = |dx: i32, dy: i32, dz: i32| {
+            let mut h = mix(self.seed ^ field.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+            for c in [ix + dx, iy + dy, iz + dz] {
+                h = mix(h ^ c as u32 as u64);
+            }
+            let (gx, gy, gz) = GRADIENTS[(h % 12) as usize];
+            gx * (fx - dx as f32) + gy * (fy - dy as f32) + gz * (fz - dz as f32)
+        };
+
+        let (u, v, w) = (fade(fx), fade(fy), fade(fz));
+        let plane = |dz: i32| {
+            let near = lerp(corner(0, 0, dz), corner(1, 0, dz), u);
+            let far = lerp(corner(0, 1, dz), corner(1, 1, dz), u);
+            lerp(near, far, v)
+        };
+        lerp(plane(0), plane(1), w)
+    }
+
+    fn hash(&self, x: i32, z: i32, octave: u64) -> u64 {
+        let h = self.seed ^ (x as u32 as u64) ^ ((z as u32 as u64) << 32);
+        mix(h.wrapping_add(octave.wrapping_mul(0x9E37_79B9_7F4A_7C15)))
+    }
+}
+
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+// splitmix64 finalizer, spreads every input bit over the whole output
+fn mix(mut h: u64) -> u64 {
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^ (h >> 31)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNKS: [Pos; 5] = [
+        Pos {x: 0, y: 0, z: 0}, Pos {x: -1, y: 0, z: -1}, Pos {x: -3, y: -2, z: 5},
+        Pos {x: 2, y: 1, z: -4}, Pos {x: -7, y: 3, z: -7},
+    ];
+
+    #[test]
+    fn regenerating_a_chunk_is_bit_identical() {
+        let (a, b) = (TerrainGenerator::new(42), TerrainGenerator::new(42));
+        for c in CHUNKS {
+            assert_eq!(a.generate(c, 16).to_bytes(), b.generate(c, 16).to_bytes(), "chunk {:?}", c);
+        }
+        // in any order
+        let backwards: Vec<Vec<u8>> = CHUNKS.iter().rev().map(|&c| a.generate(c, 16).to_bytes()).collect();
+        for (c, bytes) in CHUNKS.iter().rev().zip(backwards) {
+            assert_eq!(b.generate(*c, 16).to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn another_seed_gives_other_terrain() {
+        let (a, b) = (TerrainGenerator::new(42), TerrainGenerator::new(43));
+        let differ = CHUNKS.iter().filter(|&&c| a.generate(c, 16).to_bytes() != b.generate(c, 16).to_bytes()).count();
+        assert!(differ > 0);
+        // the column under the spawn decides where a camera starts
+        assert!((-8..8).any(|x| a.height_at(x, 0) != b.height_at(x, 0)));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::linalg::*;
+use crate::voxels::{World, Material, MaterialID, Materials};
+use crate::simulation::{TickSystem, TickContext};
+use crate::water;
+
+
+
+// Gravity for granular materials. A granular voxel falls into the air or water below it, and
+// when that is blocked it slides into a diagonal gap below one of its sides, which piles it up
+// at 45 degrees. Water it falls into is pushed up into the voxel it came from.
+// Voxels in the chunks around recent changes move every tick, random ticks catch the rest
+const DIAGONALS: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+
+
+pub struct FallingBlocks;
+
+impl TickSystem for FallingBlocks {
+    fn name(&self) -> &str {
+        "granular"
+    }
+
+    fn tick(&mut self, world: &mut World, ctx: &TickContext) {
+        for pos in ctx.active_voxels(world, |material| Material::from_id(material).is_granular()) {
+            fall(world, pos, ctx.tick);
+        }
+    }
+
+    fn random_tick(&mut self, world: &mut World, pos: Pos, material: MaterialID, ctx: &TickContext) {
+        if Material::from_id(material).is_granular() {
+            fall(world, pos, ctx.tick);
+        }
+    }
+}
+
+
+fn fall(world: &mut World, pos: Pos, tick: u64) {
+    let Some(material) = world.voxel_at(pos) else { return; };
+    if !Material::from_id(material).is_granular() {
+        return;
+    }
+
+    let below = pos.sub(&Pos::new(0, 1, 0));
+    if is_free(world, below) {
+        swap(world, pos, below, material);
+        return;
+    }
+
+    // the first side tried turns with the tick and the position, so piles don't lean one way
+    let start = (tick as usize).wrapping_add((pos.x ^ pos.z) as usize) % DIAGONALS.len();
+    for i in 0..DIAGONALS.len() {
+        let (dx, dz) = DIAGONALS[(start + i) % DIAGONALS.len()];
+        let side = pos.add(&Pos::new(dx, 0, dz));
+        let diagonal = side.sub(&Pos::new(0, 1, 0));
+        if is_free(world, side) && is_free(world, diagonal) {
+            swap(world, pos, diagonal, material);
+            return;
+        }
+    }
+}
+
+// air or water, in a loaded chunk
+fn is_free(world: &World, pos: Pos) -> bool {
+    water::level_at(world, pos).is_some()
+}
+
+fn swap(world: &mut World, from: Pos, to: Pos, material: MaterialID) {
+    let displaced = water::level_at(world, to).unwrap_or(0);
+    world.set_voxel(to, material);
+    if displaced > 0 {
+        water::set_level(world, from, displaced);
+    }
+    else {
+        world.set_voxel(from, Materials::Air as MaterialID);
+    }
+}
@@ -0,0 +1,364 @@
+use std::fmt;
+
+use sdl2::event::Event;
+use sdl2::keyboard::Scancode;
+use sdl2::mouse::MouseButton;
+
+
+
+// Keys and mouse buttons are mapped to actions, and the rest of the program only looks at actions.
+// A binding is a key or mouse button with the modifiers that have to be held for it,
+// written like W, Up, Ctrl+S or MouseLeft. With several bindings on the same key the one needing
+// the most modifiers wins, so Ctrl+S and S can do different things.
+// In the config file every action has a line like
+//   bind_move_forward = W, Up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    MoveForward,
+    MoveBack,
+    MoveLeft,
+    MoveRight,
+    // rises when flying
+    Jump,
+    Descend,
+    // held while scrolling to zoom instead of rolling
+    Zoom,
+    ToggleWalk,
+    ToggleHorizonLock,
+    RecordKeyframe,
+    TogglePlayback,
+    BreakBlock,
+    PlaceBlock,
+    // the looked at block becomes the one placed
+    PickBlock,
+    ToggleFullscreen,
+    // gives the mouse back to the desktop, clicking into the window takes it again
+    ReleaseCursor,
+    Quit,
+}
+
+pub const ACTIONS: [Action; 17] = [
+    Action::MoveForward, Action::MoveBack, Action::MoveLeft, Action::MoveRight, Action::Jump, Action::Descend,
+    Action::Zoom, Action::ToggleWalk, Action::ToggleHorizonLock, Action::RecordKeyframe, Action::TogglePlayback,
+    Action::BreakBlock, Action::PlaceBlock, Action::PickBlock, Action::ToggleFullscreen, Action::ReleaseCursor,
+    Action::Quit,
+];
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::MoveForward => "move_forward",
+            Action::MoveBack => "move_back",
+            Action::MoveLeft => "move_left",
+            Action::MoveRight => "move_right",
+            Action::Jump => "jump",
+            Action::Descend => "descend",
+            Action::Zoom => "zoom",
+            Action::ToggleWalk => "toggle_walk",
+            Action::ToggleHorizonLock => "toggle_horizon_lock",
+            Action::RecordKeyframe => "record_keyframe",
+            Action::TogglePlayback => "toggle_playback",
+            Action::BreakBlock => "break_block",
+            Action::PlaceBlock => "place_block",
+            Action::PickBlock => "pick_block",
+            Action::ToggleFullscreen => "toggle_fullscreen",
+            Action::ReleaseCursor => "release_cursor",
+            Action::Quit => "quit",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTIONS.into_iter().find(|action| action.name() == name)
+    }
+
+    fn bit(&self) -> u32 {
+        1 << (*self as u32)
+    }
+}
+
+
+pub const CTRL: u8 = 1;
+pub const SHIFT: u8 = 2;
+pub const ALT: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input {
+    Key(Scancode),
+    Mouse(MouseButton),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Binding {
+    pub input: Input,
+    pub modifiers: u8,
+}
+
+impl Binding {
+    pub fn key(scancode: Scancode) -> Binding {
+        Binding {input: Input::Key(scancode), modifiers: 0}
+    }
+
+    pub fn mouse(button: MouseButton) -> Binding {
+        Binding {input: Input::Mouse(button), modifiers: 0}
+    }
+
+    pub fn parse(text: &str) -> Result<Binding, String> {
+        let mut parts: Vec<&str> = text.split('+').map(|part| part.trim()).collect();
+        let name = parts.pop().unwrap();
+        let mut modifiers = 0;
+        for part in parts {
+            modifiers |= match part.to_ascii_lowercase().as_str() {
+                "ctrl" => CTRL,
+                "shift" => SHIFT,
+                "alt" => ALT,
+                _ => return Err(format!("unknown modifier {:?} in {:?}, use Ctrl, Shift or Alt", part, text)),
+            };
+        }
+        let input = match MOUSE_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Some(&(_, button)) => Input::Mouse(button),
+            None => Input::Key(scancode_from_name(name).ok_or(format!("unknown key {:?}", name))?),
+        };
+        Ok(Binding {input, modifiers})
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (modifier, name) in [(CTRL, "Ctrl"), (SHIFT, "Shift"), (ALT, "Alt")] {
+            if self.modifiers & modifier != 0 {
+                write!(f, "{}+", name)?;
+            }
+        }
+        match self.input {
+            Input::Key(scancode) => write!(f, "{}", scancode_name(scancode)),
+            Input::Mouse(button) => match MOUSE_NAMES.iter().find(|(_, b)| *b == button) {
+                Some((name, _)) => write!(f, "{}", name),
+                None => write!(f, "{:?}", button),
+            },
+        }
+    }
+}
+
+// a comma separated list, empty for none
+pub fn parse_bindings(text: &str) -> Result<Vec<Binding>, String> {
+    text.split(',').map(|b| b.trim()).filter(|b| !b.is_empty()).map(Binding::parse).collect()
+}
+
+
+#[derive(Clone, Debug)]
+pub struct InputMap {
+    bindings: Vec<(Binding, Action)>,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        use Scancode as S;
+        let keys = [
+            (S::W, Action::MoveForward), (S::Up, Action::MoveForward),
+            (S::S, Action::MoveBack), (S::Down, Action::MoveBack),
+            (S::A, Action::MoveLeft), (S::Left, Action::MoveLeft),
+            (S::D, Action::MoveRight), (S::Right, Action::MoveRight),
+            (S::Space, Action::Jump), (S::LShift, Action::Descend), (S::LCtrl, Action::Zoom),
+            (S::F, Action::ToggleWalk), (S::H, Action::ToggleHorizonLock),
+            (S::K, Action::RecordKeyframe), (S::P, Action::TogglePlayback),
+            (S::F11, Action::ToggleFullscreen), (S::Escape, Action::ReleaseCursor),
+        ];
+        let buttons = [
+            (MouseButton::Left, Action::BreakBlock),
+            (MouseButton::Right, Action::PlaceBlock),
+            (MouseButton::Middle, Action::PickBlock),
+        ];
+        let mut bindings: Vec<(Binding, Action)> = keys.into_iter().map(|(key, action)| (Binding::key(key), action)).collect();
+        bindings.extend(buttons.into_iter().map(|(button, action)| (Binding::mouse(button), action)));
+        bindings.push((Binding {input: Input::Key(S::Q), modifiers: CTRL}, Action::Quit));
+        InputMap {bindings}
+    }
+}
+
+impl InputMap {
+    // replaces the bindings of the action
+    pub fn bind(&mut self, action: Action, bindings: Vec<Binding>) {
+        self.bindings.retain(|(_, a)| *a != action);
+        self.bindings.extend(bindings.into_iter().map(|binding| (binding, action)));
+    }
+
+    pub fn bindings_of(&self, action: Action) -> Vec<Binding> {
+        self.bindings.iter().filter(|(_, a)| *a == action).map(|(binding, _)| *binding).collect()
+    }
+
+    // the same key with the same modifiers can't do two things
+    pub fn check_conflicts(&self) -> Result<(), String> {
+        for (i, (binding, action)) in self.bindings.iter().enumerate() {
+            if let Some((_, other)) = self.bindings[i + 1..].iter().find(|(b, a)| b == binding && a != action) {
+                return Err(format!("{} is bound to both {} and {}", binding, action.name(), other.name()));
+            }
+        }
+        Ok(())
+    }
+
+    // the binding needing the most of the held modifiers
+    pub fn action_for(&self, input: Input, modifiers: u8) -> Option<Action> {
+        self.bindings.iter()
+            .filter(|(b, _)| b.input == input && b.modifiers & modifiers == b.modifiers)
+            .max_by_key(|(b, _)| b.modifiers.count_ones())
+            .map(|(_, action)| *action)
+    }
+
+    fn actions_on(&self, input: Input) -> impl Iterator<Item = Action> + '_ {
+        self.bindings.iter().filter(move |(b, _)| b.input == input).map(|(_, action)| *action)
+    }
+}
+
+
+// Everything the player did in one frame
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameInput {
+    // actions started this frame, in order
+    pub pressed: Vec<Action>,
+    // actions held down at the end of the frame, one bit each
+    pub held: u32,
+    // mouse movement in pixels, y down
+    pub look: (i32, i32),
+    pub wheel: i32,
+    // the window was closed
+    pub quit: bool,
+}
+
+impl FrameInput {
+    pub fn is_held(&self, action: Action) -> bool {
+        self.held & action.bit() != 0
+    }
+
+    pub fn was_pressed(&self, action: Action) -> bool {
+        self.pressed.contains(&action)
+    }
+}
+
+
+// Turns window events into actions, keeping track of what is held down between frames
+pub struct InputState {
+    map: InputMap,
+    held: u32,
+    modifiers: u8,
+}
+
+impl InputState {
+    pub fn new(map: InputMap) -> InputState {
+        InputState {map, held: 0, modifiers: 0}
+    }
+
+    pub fn read_events(&mut self, events: &[Event]) -> FrameInput {
+        let mut frame = FrameInput::default();
+        for event in events {
+            match *event {
+                Event::Quit {..} => frame.quit = true,
+                // repeats would toggle things on and off while a key is held
+                Event::KeyDown {scancode: Some(scancode), repeat: false, ..} => {
+                    self.modifiers |= modifier_of(scancode);
+                    self.input_down(Input::Key(scancode), &mut frame);
+                },
+                Event::KeyUp {scancode: Some(scancode), ..} => {
+                    self.modifiers &= !modifier_of(scancode);
+                    self.input_up(Input::Key(scancode));
+                },
+                Event::MouseButtonDown {mouse_btn, ..} => self.input_down(Input::Mouse(mouse_btn), &mut frame),
+                Event::MouseButtonUp {mouse_btn, ..} => self.input_up(Input::Mouse(mouse_btn)),
+                Event::MouseMotion {xrel, yrel, ..} => {
+                    frame.look.0 += xrel;
+                    frame.look.1 += yrel;
+                },
+                Event::MouseWheel {y, ..} => frame.wheel += y,
+                _ => {},
+            }
+        }
+        frame.held = self.held;
+        frame
+    }
+
+    pub fn release_all(&mut self) {
+        self.held = 0;
+        self.modifiers = 0;
+    }
+
+    fn input_down(&mut self, input: Input, frame: &mut FrameInput) {
+        if let Some(action) = self.map.action_for(input, self.modifiers) {
+            self.held |= action.bit();
+            frame.pressed.push(action);
+        }
+    }
+
+    // letting go of a key ends everything it started, whatever the modifiers are now
+    fn input_up(&mut self, input: Input) {
+        for action in self.map.actions_on(input) {
+            self.held &= !action.bit();
+        }
+    }
+}
+
+
+fn modifier_of(scancode: Scancode) -> u8 {
+    match scancode {
+        Scancode::LCtrl | Scancode::RCtrl => CTRL,
+        Scancode::LShift | Scancode::RShift => SHIFT,
+        Scancode::LAlt | Scancode::RAlt => ALT,
+        _ => 0,
+    }
+}
+
+const MOUSE_NAMES: [(&str, MouseButton); 5] = [
+    ("MouseLeft", MouseButton::Left),
+    ("MouseRight", MouseButton::Right),
+    ("MouseMiddle", MouseButton::Middle),
+    ("MouseX1", MouseButton::X1),
+    ("MouseX2", MouseButton::X2),
+];
+
+// keys that aren't a letter, a digit or F1 to F12
+const KEY_NAMES: [(&str, Scancode); 19] = [
+    ("Space", Scancode::Space), ("Escape", Scancode::Escape), ("Tab", Scancode::Tab),
+    ("Return", Scancode::Return), ("Backspace", Scancode::Backspace), ("Delete", Scancode::Delete),
+    ("Up", Scancode::Up), ("Down", Scancode::Down), ("Left", Scancode::Left), ("Right", Scancode::Right),
+    ("LShift", Scancode::LShift), ("RShift", Scancode::RShift), ("LCtrl", Scancode::LCtrl),
+    ("RCtrl", Scancode::RCtrl), ("LAlt", Scancode::LAlt), ("RAlt", Scancode::RAlt),
+    ("PageUp", Scancode::PageUp), ("PageDown", Scancode::PageDown), ("CapsLock", Scancode::CapsLock),
+];
+
+// SDL can name keys too, but only once it is initialised
+fn scancode_from_name(name: &str) -> Option<Scancode> {
+    if let Some(&(_, scancode)) = KEY_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+        return Some(scancode);
+    }
+    let upper = name.to_ascii_uppercase();
+    match upper.as_bytes() {
+        [c @ b'A'..=b'Z'] => Scancode::from_i32(Scancode::A as i32 + (c - b'A') as i32),
+        [b'0'] => Some(Scancode::Num0),
+        [c @ b'1'..=b'9'] => Scancode::from_i32(Scancode::Num1 as i32 + (c - b'1') as i32),
+        [b'F', ..] => match upper[1..].parse::<i32>() {
+            Ok(n @ 1..=12) => Scancode::from_i32(Scancode::F1 as i32 + n - 1),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn scancode_name(scancode: Scancode) -> String {
+    if let Some((name, _)) = KEY_NAMES.iter().find(|(_, s)| *s == scancode) {
+        return name.to_string();
+    }
+    let n = scancode as i32;
+    if (Scancode::A as i32..=Scancode::Z as i32).contains(&n) {
+        ((b'A' + (n - Scancode::A as i32) as u8) as char).to_string()
+    }
+    else if scancode == Scancode::Num0 {
+        "0".to_string()
+    }
+    else if (Scancode::Num1 as i32..=Scancode::Num9 as i32).contains(&n) {
+        (n - Scancode::Num1 as i32 + 1).to_string()
+    }
+    else if (Scancode::F1 as i32..=Scancode::F12 as i32).contains(&n) {
+        format!("F{}", n - Scancode::F1 as i32 + 1)
+    }
+    else {
+        format!("{:?}", scancode)
+    }
+}
@@ -22,8 +22,9 @@ impl Vec3 {
         Vec3 {x: x, y: y, z: z}
     }
     
+    // the voxel containing this point, rounding towards negative infinity so it works on both sides of the origin
     pub fn pos(&self) -> Pos {
-        Pos::new(self.x as i32, self.y as i32, self.z as i32)
+        Pos::new(self.x.floor() as i32, self.y.floor() as i32, self.z.floor() as i32)
     }
 
     pub fn from_polar(len: f32, pitch: f32, yaw: f32) -> Vec3 {
@@ -165,7 +166,121 @@ impl Vec3 {
 }
 
 
+// Rotations as unit quaternions. Composing them and normalizing the result keeps them exact
+// rotations, where rotating basis vectors one by one lets them drift apart
 #[derive(Debug, Copy, PartialEq, Clone)]
+pub struct Quat {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Quat {
+    pub fn identity() -> Quat {
+        Quat {w: 1.0, x: 0.0, y: 0.0, z: 0.0}
+    }
+
+    // right handed rotation of angle radians around axis
+    pub fn from_axis_angle(axis: &Vec3, angle: f32) -> Quat {
+        let a = axis.normalize();
+        let (sin, cos) = (angle / 2.0).sin_cos();
+        Quat {w: cos, x: a.x * sin, y: a.y * sin, z: a.z * sin}
+    }
+
+    // the rotation that turns the z axis into forward and the y axis into up.
+    // up is made perpendicular to forward first, and replaced if both are parallel
+    pub fn look_rotation(forward: &Vec3, up: &Vec3) -> Quat {
+        let f = forward.normalize();
+        let mut r = up.cross(&f).normalize();
+        if r.null() {
+            r = Y_AXIS.cross(&f).normalize();
+            if r.null() {
+                r = X_AXIS;
+            }
+        }
+        let u = f.cross(&r);
+
+        // rotation matrix with columns r, u, f
+        let (m00, m01, m02) = (r.x, u.x, f.x);
+        let (m10, m11, m12) = (r.y, u.y, f.y);
+        let (m20, m21, m22) = (r.z, u.z, f.z);
+        let trace = m00 + m11 + m22;
+        let q = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            Quat {w: s / 4.0, x: (m21 - m12) / s, y: (m02 - m20) / s, z: (m10 - m01) / s}
+        }
+        else if m00 > m11 && m00 > m22 {
+            let s = (1.0 + m00 - m11 - m22).sqrt() * 2.0;
+            Quat {w: (m21 - m12) / s, x: s / 4.0, y: (m01 + m10) / s, z: (m02 + m20) / s}
+        }
+        else if m11 > m22 {
+            let s = (1.0 + m11 - m00 - m22).sqrt() * 2.0;
+            Quat {w: (m02 - m20) / s, x: (m01 + m10) / s, y: s / 4.0, z: (m12 + m21) / s}
+        }
+        else {
+            let s = (1.0 + m22 - m00 - m11).sqrt() * 2.0;
+            Quat {w: (m10 - m01) / s, x: (m02 + m20) / s, y: (m12 + m21) / s, z: s / 4.0}
+        };
+        q.normalize()
+    }
+
+    // the rotation of other followed by self
+    pub fn mul(&self, other: &Quat) -> Quat {
+        Quat {
+            w: self.w * other.w - self.x * other.x - self.y * other.y - self.z * other.z,
+            x: self.w * other.x + self.x * other.w + self.y * other.z - self.z * other.y,
+            y: self.w * other.y - self.x * other.z + self.y * other.w + self.z * other.x,
+            z: self.w * other.z + self.x * other.y - self.y * other.x + self.z * other.w,
+        }
+    }
+
+    pub fn dot(&self, other: &Quat) -> f32 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    // turns at a constant rate from self at t = 0 to other at t = 1, the short way around
+    pub fn slerp(&self, other: &Quat, t: f32) -> Quat {
+        let mut cos = self.dot(other);
+        let mut to = *other;
+        if cos < 0.0 {
+            cos = -cos;
+            to = Quat {w: -to.w, x: -to.x, y: -to.y, z: -to.z};
+        }
+        let (a, b) = if cos > 0.9995 {
+            // nearly the same rotation, a straight line is as good and doesn't divide by ~0
+            (1.0 - t, t)
+        }
+        else {
+            let angle = cos.acos();
+            let sin = angle.sin();
+            (((1.0 - t) * angle).sin() / sin, (t * angle).sin() / sin)
+        };
+        Quat {
+            w: self.w * a + to.w * b,
+            x: self.x * a + to.x * b,
+            y: self.y * a + to.y * b,
+            z: self.z * a + to.z * b,
+        }.normalize()
+    }
+
+    pub fn normalize(&self) -> Quat {
+        let l = self.dot(self).sqrt();
+        if l == 0.0 {
+            return Quat::identity();
+        }
+        Quat {w: self.w / l, x: self.x / l, y: self.y / l, z: self.z / l}
+    }
+
+    pub fn rotate(&self, v: &Vec3) -> Vec3 {
+        let q = Vec3::new(self.x, self.y, self.z);
+        let t = q.cross(v).scale(2.0);
+        v.add(&t.scale(self.w)).add(&q.cross(&t))
+    }
+}
+
+
+#[derive(Debug, Copy, PartialEq, Eq, Hash, Clone)]
 pub struct Pos {
     pub x: i32,
     pub y: i32,
@@ -194,12 +309,57 @@ impl Pos {
     }
 
     pub fn add_mod(&self, other: &Pos, modulo: i32) -> Pos {
-        Pos::new((self.x + other.x) % modulo, (self.y + other.y) % modulo, (self.z + other.z) % modulo)
+        self.add(other).modulo(modulo)
+    }
+
+    pub fn scale(&self, i: i32) -> Pos {
+        Pos::new(self.x * i, self.y * i, self.z * i)
     }
 
+    // floor division, so -1 / 8 is -1 and not 0
     pub fn div(&self, i: i32) -> Pos {
-        Pos::new(self.x / i, self.y / i, self.z / i)
+        Pos::new(self.x.div_euclid(i), self.y.div_euclid(i), self.z.div_euclid(i))
+    }
+
+    // remainder matching div, always in 0..i
+    pub fn modulo(&self, i: i32) -> Pos {
+        Pos::new(self.x.rem_euclid(i), self.y.rem_euclid(i), self.z.rem_euclid(i))
     }
     
 
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE: i32 = 8;
+
+    #[test]
+    fn div_rounds_towards_negative_infinity() {
+        assert_eq!(Pos::new(-1, 0, -CHUNK_SIZE).div(CHUNK_SIZE), Pos::new(-1, 0, -1));
+        assert_eq!(Pos::new(CHUNK_SIZE - 1, -CHUNK_SIZE - 1, CHUNK_SIZE).div(CHUNK_SIZE), Pos::new(0, -2, 1));
+    }
+
+    #[test]
+    fn modulo_stays_inside_the_chunk() {
+        assert_eq!(Pos::new(-1, 0, -CHUNK_SIZE).modulo(CHUNK_SIZE), Pos::new(CHUNK_SIZE - 1, 0, 0));
+        assert_eq!(Pos::new(CHUNK_SIZE - 1, -CHUNK_SIZE - 1, CHUNK_SIZE).modulo(CHUNK_SIZE), Pos::new(CHUNK_SIZE - 1, CHUNK_SIZE - 1, 0));
+    }
+
+    #[test]
+    fn div_and_modulo_put_the_position_back_together() {
+        for x in -2 * CHUNK_SIZE..2 * CHUNK_SIZE {
+            let pos = Pos::new(x, -x, x);
+            assert_eq!(pos.div(CHUNK_SIZE).scale(CHUNK_SIZE).add(&pos.modulo(CHUNK_SIZE)), pos);
+        }
+    }
+
+    #[test]
+    fn points_floor_to_their_voxel() {
+        assert_eq!(Vec3::new(-0.5, 0.0, 0.5).pos(), Pos::new(-1, 0, 0));
+        assert_eq!(Vec3::new(-1.0, -8.0, -8.01).pos(), Pos::new(-1, -8, -9));
+        assert_eq!(Vec3::new(7.99, -0.0, 8.0).pos(), Pos::new(7, 0, 8));
+    }
+}
@@ -1,11 +1,8 @@
 
-use std::ops::Index;
-
 use std::time::Instant;
+use std::path::{Path, PathBuf};
 
-use sdl2::keyboard::Scancode;
-use sdl2::event::Event;
-use sdl2::mouse::MouseButton;
+use sdl2::event::{Event, WindowEvent};
 
 pub mod linalg;
 pub use linalg::*;
@@ -13,19 +10,32 @@ pub mod voxels;
 pub use voxels::*;
 pub mod camera;
 pub use camera::{Camera, Screen};
-
-
-// Aliases
-type Keys = [bool; 256];
-impl Index<Key> for Keys {
-    type Output = bool;
-
-    fn index(&self, key: Key) -> &Self::Output {
-        match key {
-            _ => &self[key as usize],
-        }
-    }
-}
+pub mod storage;
+pub use storage::RegionStore;
+pub mod compression;
+pub mod generation;
+pub mod structures;
+pub mod simulation;
+pub use simulation::Simulation;
+pub mod water;
+pub mod granular;
+pub mod savefile;
+pub mod vox;
+pub use vox::VoxScene;
+pub mod mesh;
+pub use mesh::Mesh;
+pub mod physics;
+pub use physics::{Player, MoveMode};
+pub mod path;
+pub use path::{CameraPath, Keyframe, Playback};
+pub mod render;
+pub use render::Frame;
+pub mod config;
+pub use config::Config;
+pub mod input;
+pub use input::{Action, FrameInput, InputMap, InputState};
+pub mod replay;
+pub use replay::{InputRecording, InputRecorder};
 
 
 // Data clumps
@@ -34,57 +44,40 @@ struct Settings {
     scroll_sensitivity: f32,
     zoom_sensitivity: f32,
     camera_speed: f32,
+    walk_speed: f32,
 }
 
+struct RenderSettings {
+    // a .y4m video, or a directory for numbered images
+    output: PathBuf,
+    width: u32,
+    height: u32,
+    samples: u32,
+    fps: u32,
+}
 
-
-enum Key {
-    W,
-    A,
-    S,
-    D,
-    
-    /*Up,
-    Down,
-    Left,
-    Right, */
-
-    Space,
-    Shift,
-    Ctrl,
-    Esc,
-    
-    MouseLeft,
-    MouseRight,
-    MouseMiddle,
+struct Flythrough {
+    path: CameraPath,
+    speed: f32,
+    playback: Option<Playback>,
+    // only a path with new keyframes gets saved
+    recorded: bool,
 }
 
-impl Key {
-    fn from_scancode(scancode: Scancode) -> Option<Key> {
-        match scancode {
-            Scancode::W => Some(Key::W),
-            Scancode::A => Some(Key::A),
-            Scancode::S => Some(Key::S),
-            Scancode::D => Some(Key::D),
-            Scancode::Space => Some(Key::Space),
-            Scancode::LShift => Some(Key::Shift),
-            Scancode::LCtrl => Some(Key::Ctrl),
-            Scancode::Escape => Some(Key::Esc),
-            _ => None,
+impl Flythrough {
+    fn toggle_playback(&mut self) {
+        if self.playback.take().is_some() {
+            return;
         }
-    }
-
-    fn from_mouse(mouse_btn: sdl2::mouse::MouseButton) -> Option<Key> {
-        match mouse_btn {
-            MouseButton::Left => Some(Key::MouseLeft),
-            MouseButton::Right => Some(Key::MouseRight),
-            MouseButton::Middle => Some(Key::MouseMiddle),
-            _ => None,
+        match Playback::new(self.path.clone(), self.speed) {
+            Ok(playback) => self.playback = Some(playback),
+            Err(e) => println!("can't play the camera path: {}", e),
         }
     }
 }
 
 
+
 //Globals
 
 
@@ -93,134 +86,336 @@ impl Key {
 
 // functions
 
-fn generate_world() ->  Box<World> {
-    let mut world = World::new();
-    world.random_gen();
+// a world loaded from a save keeps the seed it was saved with
+fn generate_world(params: WorldParams, seed: u64, save_dir: Option<&Path>, world_file: Option<&Path>) -> Result<Box<World>, String> {
+    let mut world = match world_file {
+        Some(file) if file.exists() => World::load(file, save_dir)?,
+        _ => {
+            let mut world = World::new(params, seed);
+            if let Some(dir) = save_dir {
+                world.attach_store(RegionStore::open(dir, params.chunk_size, seed)?);
+            }
+            world
+        }
+    };
+    world.load_area();
     //let mut c = Chunk::new(Pos::new(0, 0, 0));
     //c.set_voxel(Vec3::new(4.0, 4.0, 4.0), Materials::Stone as MaterialID);
     //c.random_gen();
     //world.load_chunk(c);
-    world
+    Ok(world)
 }
 
 
-fn tick() {
+// Renders every frame of a flythrough, as fast as it goes. Time only passes in steps of a frame,
+// so the same path gives the same video
+fn render_flythrough(camera: &mut Camera, simulation: &mut Simulation, mut playback: Playback, settings: &RenderSettings) -> Result<(), String> {
+    let dt = 1.0 / settings.fps as f32;
+    let is_video = settings.output.extension().is_some_and(|e| e == "y4m");
+    let mut video = None;
+    if is_video {
+        video = Some(render::Y4mWriter::create(&settings.output, settings.width, settings.height, settings.fps)?);
+    }
+    else {
+        std::fs::create_dir_all(&settings.output).map_err(|e| format!("can't create {}: {}", settings.output.display(), e))?;
+    }
 
+    while let Some(keyframe) = playback.next_frame(dt) {
+        keyframe.apply(camera);
+        simulation.advance(camera.world_mut(), dt);
+        let frame = camera.render(settings.width, settings.height, settings.samples);
+        match &mut video {
+            Some(video) => video.write_frame(&frame)?,
+            None => frame.write_ppm(&settings.output.join(format!("frame_{:05}.ppm", playback.frames() - 1)))?,
+        }
+        println!("rendered frame {}", playback.frames());
+    }
+    if let Some(video) = video {
+        video.finish()?;
+    }
+    println!("{} frames in {:.2} s", playback.frames(), playback.seconds());
+    Ok(())
 }
- 
 
-fn user_inputs(sdl_ctx: &mut sdl2::Sdl, cfg: &Settings, camera: &mut Camera, key_states: &mut Keys, dt: f32) -> bool {
-    let (center_x, center_y) = camera.screen.get_screen_center_pix();
-    
-    let mut events = sdl_ctx.event_pump().unwrap();
-    for event in events.poll_iter() {
-        match event {
-            Event::Quit {..} => {
-                return true;
-            },
 
-            Event::KeyDown { scancode, .. } => {
-                let key = match Key::from_scancode(scancode.unwrap()) {
-                    Some(key) => key,
-                    None => continue,
-                };
-                match key {
-                    Key::Esc => return true,
-                    _ => {},
-                }
-
-                key_states[key as usize] = true;
-            },
+// Casts the same bundle of rays from just above the terrain with and without empty space skipping
+fn benchmark_traversal(params: WorldParams) {
+    const DIRECTIONS: usize = 20_000;
+    const ROUNDS: usize = 20;
+
+    let world = generate_world(params, 0, None, None).unwrap();
+    let origin = Vec3::new(0.5, world.generator().spawn_height(0, 0) as f32 + 0.5, 0.5);
+    let rays: Vec<Ray> = (0..DIRECTIONS).map(|i| {
+        // fibonacci sphere, so the directions are spread evenly
+        let y = 1.0 - 2.0 * (i as f32 + 0.5) / DIRECTIONS as f32;
+        let yaw = i as f32 * std::f32::consts::PI * (3.0 - 5.0_f32.sqrt());
+        Ray::new(origin, Vec3::from_polar(1.0, y.asin(), yaw))
+    }).collect();
+
+    let mut results = Vec::new();
+    for skip_empty in [false, true] {
+        let start = Instant::now();
+        let mut hits = 0;
+        for _ in 0..ROUNDS {
+            for ray in &rays {
+                let color = ray.march(&world, 0, skip_empty);
+                hits += (color.sdl_format().a != 0) as usize;
+            }
+        }
+        let elapsed = start.elapsed().as_secs_f32();
+        println!("skip_empty={:<5} {:>8.2} ms  {:>12.0} rays/s  hits={}", 
+            skip_empty, elapsed * 1000.0, (DIRECTIONS * ROUNDS) as f32 / elapsed, hits);
+        results.push(elapsed);
+    }
+    println!("speedup: {:.2}x", results[0] / results[1]);
+    println!("{}", world.memory_stats());
+}
 
-            Event::KeyUp { scancode, .. } => {
-                let key = match Key::from_scancode(scancode.unwrap()) {
-                    Some(key) => key,
-                    None => continue,
-                };
-                
-                key_states[key as usize] = false;
-            },
 
-            Event::MouseMotion { xrel, yrel, .. } => {
-                let yaw = xrel as f32 * cfg.mouse_sensitivity;
-                camera.rotate_yaw(yaw);
-                
-                let pitch = -yrel as f32 * cfg.mouse_sensitivity;
-                camera.rotate_pitch(pitch);
 
-                // setting the mouse to the center
-                sdl_ctx.mouse().warp_mouse_in_window(camera.get_window(), center_x, center_y);
+// handles what only concerns the window, and turns the rest into actions
+fn user_inputs(screen: &mut Screen, input: &mut InputState) -> FrameInput {
+    let mut events = Vec::new();
+    let mut focus_lost = false;
+    for event in screen.events().poll_iter().collect::<Vec<Event>>() {
+        match event {
+            Event::Window {win_event: WindowEvent::SizeChanged(width, height), ..} => {
+                screen.resize(width.max(1) as u32, height.max(1) as u32);
             },
-
-            Event::MouseWheel { y, .. } => {
-                if key_states[Key::Ctrl] {
-                    let zoom = (y as f32) * cfg.zoom_sensitivity;
-                    camera.zoom(zoom);
-                } 
-                else {
-                    let roll = (y as f32) * cfg.scroll_sensitivity;
-                    camera.rotate_roll(roll);
-                }
+            Event::Window {win_event: WindowEvent::FocusLost, ..} => {
+                screen.capture_mouse(false);
+                focus_lost = true;
             },
+            // the click taking the mouse back doesn't do anything else
+            Event::MouseButtonDown {..} if !screen.mouse_captured() => screen.capture_mouse(true),
+            Event::MouseMotion {..} | Event::MouseWheel {..} if !screen.mouse_captured() => {},
+            event => events.push(event),
+        }
+    }
 
-            Event::MouseButtonDown {mouse_btn, .. } => {
-                //clicks tells you how many clicks it was. Ex: 1 for single click, 2 for double click, etc.
-                if let Some(key) = Key::from_mouse(mouse_btn) {
-                    key_states[key as usize] = true;
-                }
-                // atm nothing is done to know the position of where mouse was clicked, because its always in the center
-            },
+    let mut frame = input.read_events(&events);
+    if focus_lost {
+        // keys let go while the window wasn't looking would stay held
+        input.release_all();
+        frame.held = 0;
+    }
+    if frame.was_pressed(Action::ReleaseCursor) {
+        screen.capture_mouse(false);
+    }
+    if frame.was_pressed(Action::ToggleFullscreen) {
+        if let Err(e) = screen.toggle_fullscreen() {
+            println!("can't toggle fullscreen: {}", e);
+        }
+    }
+    frame
+}
 
-            Event::MouseButtonUp {mouse_btn, .. } => {
-                if let Some(key) = Key::from_mouse(mouse_btn) {
-                    key_states[key as usize] = false;
-                }
+// returns whether to quit
+fn apply_inputs(frame: &FrameInput, cfg: &Settings, camera: &mut Camera, player: &mut Player, flythrough: &mut Flythrough, dt: f32) -> bool {
+    if frame.quit {
+        return true;
+    }
+    for action in &frame.pressed {
+        match action {
+            Action::Quit => return true,
+            Action::ToggleWalk => player.toggle_mode(),
+            Action::ToggleHorizonLock => camera.set_horizon_locked(!camera.is_horizon_locked()),
+            Action::RecordKeyframe => {
+                flythrough.path.push(Keyframe::of(camera));
+                flythrough.recorded = true;
+                println!("recorded keyframe {}", flythrough.path.len());
             },
+            Action::TogglePlayback => flythrough.toggle_playback(),
+            Action::BreakBlock | Action::PlaceBlock | Action::PickBlock => edit_block(*action, camera, player),
+            _ => {},
+        }
+    }
+
+    let (look_x, look_y) = frame.look;
+    camera.rotate_yaw(look_x as f32 * cfg.mouse_sensitivity);
+    camera.rotate_pitch(-look_y as f32 * cfg.mouse_sensitivity);
 
-            _ => {}
+    if frame.wheel != 0 {
+        if frame.is_held(Action::Zoom) {
+            camera.zoom(frame.wheel as f32 * cfg.zoom_sensitivity);
+        }
+        else {
+            camera.rotate_roll(frame.wheel as f32 * cfg.scroll_sensitivity);
         }
     }
-    
-    let mov_x = (key_states[Key::A] as i32 - key_states[Key::D] as i32) as f32;
-    let mov_y = (key_states[Key::Space] as i32 - key_states[Key::Shift] as i32) as f32;
-    let mov_z = (key_states[Key::W] as i32 - key_states[Key::S] as i32) as f32;
-    let mov = Vec3::new(mov_x, mov_y, mov_z).normalize().scale(dt * cfg.camera_speed);
 
-    camera.move_rel_to_facing(mov);
+    // the path moves the camera while it plays
+    if flythrough.playback.is_some() {
+        return false;
+    }
+
+    let axis = |positive: Action, negative: Action| (frame.is_held(positive) as i32 - frame.is_held(negative) as i32) as f32;
+    let mov_x = axis(Action::MoveLeft, Action::MoveRight);
+    let mov_y = axis(Action::Jump, Action::Descend);
+    let mov_z = axis(Action::MoveForward, Action::MoveBack);
+
+    match player.mode {
+        MoveMode::Fly => {
+            let mov = Vec3::new(mov_x, mov_y, mov_z).normalize().scale(dt * cfg.camera_speed);
+            camera.move_rel_to_facing(mov);
+        },
+        MoveMode::Walk => {
+            let walked = camera.facing_to_world(Vec3::new(mov_x, 0.0, mov_z).normalize().scale(dt * cfg.walk_speed));
+            let eye = player.walk(camera.world(), camera.position(), walked, frame.is_held(Action::Jump), dt);
+            camera.set_position(eye);
+        },
+    }
     
     false
 }
 
+// acts on the block in the middle of the screen
+fn edit_block(action: Action, camera: &mut Camera, player: &mut Player) {
+    // how far away blocks can be reached
+    const REACH: f32 = 6.0;
+
+    let ray = Ray::new(camera.position(), camera.forward());
+    let Some((hit, before)) = ray.cast(camera.world(), REACH) else { return; };
+    match action {
+        Action::BreakBlock => {
+            camera.world_mut().set_voxel(hit, Materials::Air as MaterialID);
+        },
+        // not inside the player
+        Action::PlaceBlock if !physics::body_overlaps(camera.position(), before) => {
+            camera.world_mut().set_voxel(before, player.block);
+        },
+        Action::PickBlock => {
+            player.block = camera.world().voxel_at(hit).unwrap_or(player.block);
+        },
+        _ => {},
+    }
+}
+
 fn main() -> Result<(), String> {
-    const SCREEN_WIDTH_PIX: u32 = 320;
-    const SCREEN_HEIGHT_PIX: u32 = 180;
-    const PIXELS_PER_UNIT: u32 = 100;
-    const PIXEL_SIZE: u8 = 2;
-    let fps: f32 = 3.0;
-
-    let camera_pos = Vec3::new(0.0, 0.0, 0.0);
-    let camera_dir = Vec3::new(0.0, 0.0, 1.0);
-    let camera_up = Vec3::new(0.0, 1.0, 0.0);
-    let fov: f32 = 90.0;
-
-    let mouse_sensitivity: f32 = 0.2;
-    let scroll_sensitivity: f32 = 0.1;
-    let zoom_sensitivity: f32 = 0.1;
-    let camera_speed: f32 = 10.0;
-
-    let world = generate_world();
-    
-    let mut sdl_ctx: sdl2::Sdl = sdl2::init()?;
-    let screen = Screen::new(&mut sdl_ctx, SCREEN_WIDTH_PIX, SCREEN_HEIGHT_PIX, PIXEL_SIZE, "RayTracer").unwrap();
-    let mut camera = Camera::new(screen, world, camera_pos, camera_dir, camera_up, fov, PIXELS_PER_UNIT);
+    let args: Vec<String> = std::env::args().collect();
+    let mut config = Config::from_args(&args)?;
+    let replay_recording = config.replay_input.as_deref().map(InputRecording::load).transpose()?;
+    // replays use the settings they were recorded with, whatever the config says
+    if let Some(recording) = &replay_recording {
+        for name in config::RECORDED_SETTINGS {
+            let (_, value) = recording.settings.iter().find(|(recorded, _)| recorded == name)
+                .ok_or(format!("the input recording has no {} setting", name))?;
+            config.set(name, value).map_err(|e| format!("recorded {}: {}", name, e))?;
+        }
+    }
+    let world_params = config.world_params()?;
+
+    if args.iter().any(|arg| arg == "--bench") {
+        benchmark_traversal(world_params);
+        return Ok(());
+    }
+
+    let fps = config.fps;
+
+    // Recording and replaying start from the generated terrain and don't touch the saves, which
+    // would otherwise differ between the recording and every replay of it
+    let isolated = replay_recording.is_some() || config.record_input.is_some();
+    let mut world = match &replay_recording {
+        Some(recording) => generate_world(world_params, recording.seed, None, None)?,
+        None if isolated => generate_world(world_params, config.seed, None, None)?,
+        None => generate_world(world_params, config.seed, Some(&config.save_dir), config.world.as_deref())?,
+    };
+    if isolated {
+        println!("recording or replaying input, the save directory and world file are left alone");
+    }
+    println!("world seed: {}", world.seed());
+    let camera_pos = config.camera_position
+        .unwrap_or(Vec3::new(0.5, world.generator().spawn_height(0, 0) as f32 + 1.5, 0.5));
+    if let Some(file) = &config.import_vox {
+        let scene = VoxScene::load(file)?;
+        let written = scene.stamp(&mut world, config.vox_at);
+        println!("imported {} voxels from {}", written, file.display());
+    }
+    println!("{}", world.memory_stats());
+
+    // exporting writes the region and quits without opening a window, like rendering
+    if let Some((min, max)) = config.export_region {
+        if let Some(file) = &config.export_vox {
+            VoxScene::from_region(&mut world, min, max)?.save(file)?;
+            println!("exported the region to {}", file.display());
+        }
+        if let Some(file) = &config.export_mesh {
+            Mesh::from_region(&mut world, min, max)?.save(file)?;
+            println!("exported the region to {}", file.display());
+        }
+        return world.flush();
+    }
     
-    let mut key_states: Keys = [false; 256];
+    let mut camera = Camera::new(world, camera_pos, config.camera_direction, config.camera_up, config.fov.to_radians(), config.pixels_per_unit);
+    camera.set_horizon_locked(config.horizon_lock);
+
+    let mut flythrough = Flythrough {
+        path: match &config.path {
+            Some(file) if file.exists() => CameraPath::load(file)?,
+            _ => CameraPath::new(),
+        },
+        speed: config.path_speed,
+        playback: None,
+        recorded: false,
+    };
+    // benchmark runs play the path once and quit
+    let play_path = args.iter().any(|arg| arg == "--play-path");
+    if play_path {
+        flythrough.playback = Some(Playback::new(flythrough.path.clone(), config.path_speed)?);
+    }
+
+    let mut simulation = Simulation::new(camera.world().seed());
+    simulation.register(water::WaterFlow);
+    simulation.register(granular::FallingBlocks);
+
+    if let Some(output) = &config.render {
+        let (width, height) = config.render_size;
+        let settings = RenderSettings {
+            output: output.clone(),
+            width,
+            height,
+            samples: config.render_samples,
+            fps: config.render_fps,
+        };
+        let playback = Playback::new(flythrough.path.clone(), config.path_speed)
+            .map_err(|e| format!("rendering needs a path with keyframes: {}", e))?;
+        render_flythrough(&mut camera, &mut simulation, playback, &settings)?;
+        return camera.world_mut().flush();
+    }
 
-    let config = Settings {
-        mouse_sensitivity,
-        scroll_sensitivity,
-        zoom_sensitivity,
-        camera_speed
+    // replays start where the recording did
+    let mut replay = None;
+    let mut recorder = None;
+    if let Some(recording) = replay_recording {
+        recording.start.apply(&mut camera);
+        replay = Some(recording.frames.into_iter());
+    }
+    else if let Some(file) = &config.record_input {
+        let start = Keyframe::of(&camera);
+        start.apply(&mut camera);
+        recorder = Some(InputRecorder::create(file, camera.world().seed(), start, &config.recorded_settings())?);
+    }
+
+    // a headless replay runs as fast as it can, without a window
+    let headless = args.iter().any(|arg| arg == "--headless");
+    if headless && replay.is_none() {
+        return Err("--headless only works with replay_input".to_string());
+    }
+    let mut sdl_ctx = if headless { None } else { Some(sdl2::init()?) };
+    let mut screen = match &mut sdl_ctx {
+        Some(sdl_ctx) => Some(Screen::new(sdl_ctx, config.screen_width, config.screen_height, config.pixel_size, "RayTracer")?),
+        None => None,
+    };
+    
+    let mut input = InputState::new(config.bindings.clone());
+    let mut player = Player::new();
+
+    let settings = Settings {
+        mouse_sensitivity: config.mouse_sensitivity,
+        scroll_sensitivity: config.scroll_sensitivity,
+        zoom_sensitivity: config.zoom_sensitivity,
+        camera_speed: config.camera_speed,
+        walk_speed: config.walk_speed,
     };
     
     
@@ -228,20 +423,68 @@ fn main() -> Result<(), String> {
     let mut dt = target_dt;
     const SEC_NANOS : f32 = 1_000_000_000.0;
     
+    let mut frames: u64 = 0;
     loop {
         let last_time = Instant::now();
-
-        // game logic
-        tick();
+        let replayed = match &mut replay {
+            Some(recording) => match recording.next() {
+                Some(frame) => Some(frame),
+                None => {
+                    let (p, f) = (camera.position(), camera.forward());
+                    println!("replayed {} frames, camera at {} {} {} facing {} {} {}", frames, p.x, p.y, p.z, f.x, f.y, f.z);
+                    break;
+                },
+            },
+            None => None,
+        };
+        // the last frame lasted at least target_dt because of the sleep
+        let frame_seconds = match &replayed {
+            Some((recorded_dt, _)) => *recorded_dt,
+            None => dt.max(target_dt) as f32 / SEC_NANOS,
+        };
+        frames += 1;
+
+        // game logic, at its own fixed rate
+        simulation.advance(camera.world_mut(), frame_seconds);
+
+        if let Some(playback) = &mut flythrough.playback {
+            match playback.next_frame(frame_seconds) {
+                Some(keyframe) => keyframe.apply(&mut camera),
+                None => {
+                    println!("flythrough: {} frames in {:.2} s, {:.2} FPS", 
+                        playback.frames(), playback.seconds(), playback.frames() as f32 / playback.seconds());
+                    flythrough.playback = None;
+                    if play_path {break;}
+                },
+            }
+        }
 
         // rendering
-        camera.draw_frame();
+        if let Some(screen) = &mut screen {
+            screen.present(&camera.render(screen.width_pix, screen.height_pix, 1));
+        }
 
         // user input
-        let stop = user_inputs(&mut sdl_ctx, &config, &mut camera, &mut key_states, dt as f32 / SEC_NANOS);
+        let frame_input = match (replayed, &mut screen) {
+            (Some((_, recorded)), Some(screen)) => {
+                // only closing the window still works
+                if screen.events().poll_iter().any(|event| matches!(event, Event::Quit {..})) {break;}
+                recorded
+            },
+            (Some((_, recorded)), None) => recorded,
+            (None, Some(screen)) => user_inputs(screen, &mut input),
+            (None, None) => break,
+        };
+        if let Some(recorder) = &mut recorder {
+            recorder.record(frame_seconds, &frame_input)?;
+        }
+        let stop = apply_inputs(&frame_input, &settings, &mut camera, &mut player, &mut flythrough, frame_seconds);
         if stop {break;}
 
         // timing
+        if screen.is_none() {
+            continue;
+        }
         let current_time = Instant::now();
         dt = current_time.duration_since(last_time).as_nanos() as u64;
 
@@ -251,6 +494,19 @@ fn main() -> Result<(), String> {
         println!("FPS: {:.2}", SEC_NANOS / dt.max(target_dt) as f32);
 
     }
+
+    // edits of the chunks still in memory would be lost otherwise
+    if !isolated {
+        camera.world_mut().flush()?;
+        if let Some(file) = &config.world {
+            camera.world().save(file)?;
+        }
+    }
+    if flythrough.recorded && config.replay_input.is_none() {
+        let file = config.path.clone().unwrap_or(PathBuf::from(path::DEFAULT_PATH_FILE));
+        flythrough.path.save(&file)?;
+        println!("saved {} keyframes to {}, play them with --path {}", flythrough.path.len(), file.display(), file.display());
+    }
     Ok(())
 }
 
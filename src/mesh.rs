@@ -0,0 +1,225 @@
+use std::fs;
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::linalg::*;
+use crate::voxels::{World, Material, MaterialID, Materials};
+
+
+
+// Polygon mesh of a region of the world. Faces between two solid voxels are culled,
+// the remaining ones are merged greedily into the largest rectangles of the same material.
+// Meshing works in world coordinates, so faces on chunk borders are culled and merged like any other
+pub struct Quad {
+    // counter clockwise when looking at the front of the face
+    pub corners: [Pos; 4],
+    pub normal: Pos,
+    pub material: MaterialID,
+}
+
+
+pub struct Mesh {
+    pub quads: Vec<Quad>,
+}
+
+impl Mesh {
+    // voxels outside of min..=max count as air, so the mesh of the region is closed
+    pub fn from_region(world: &mut World, min: Pos, max: Pos) -> Result<Mesh, String> {
+        if min.x > max.x || min.y > max.y || min.z > max.z {
+            return Err(format!("region {:?} to {:?} is empty", min, max));
+        }
+        let chunk_size = world.params().chunk_size as i32;
+
+        let lo = [min.x, min.y, min.z];
+        let hi = [max.x, max.y, max.z];
+        // chunks are loaded as the slices reach them and released once the slices are past them
+        let solid_at = |world: &mut World, p: [i32; 3]| -> MaterialID {
+            if (0..3).any(|axis| p[axis] < lo[axis] || p[axis] > hi[axis]) {
+                return Materials::Air as MaterialID;
+            }
+            let pos = Pos::new(p[0], p[1], p[2]);
+            world.ensure_loaded(world.split_pos(pos).0);
+            world.voxel_at(pos).unwrap_or(Materials::Air as MaterialID)
+        };
+
+        let mut quads = Vec::new();
+        for d in 0..3 {
+            // u and v follow d cyclically, so u x v points along +d
+            let u = (d + 1) % 3;
+            let v = (d + 2) % 3;
+            let width = (hi[u] - lo[u] + 1) as usize;
+            let height = (hi[v] - lo[v] + 1) as usize;
+
+            for sign in [1, -1] {
+                let mut mask: Vec<MaterialID> = vec![0; width * height];
+                for slice in lo[d]..=hi[d] {
+                    if slice.rem_euclid(chunk_size) == 0 {
+                        world.release_chunks();
+                    }
+                    for j in 0..height {
+                        for i in 0..width {
+                            let mut p = [0; 3];
+                            p[d] = slice;
+                            p[u] = lo[u] + i as i32;
+                            p[v] = lo[v] + j as i32;
+                            let material = solid_at(world, p);
+                            p[d] += sign;
+                            let visible = material != Materials::Air as MaterialID && solid_at(world, p) == Materials::Air as MaterialID;
+                            mask[j * width + i] = if visible { material } else { 0 };
+                        }
+                    }
+
+                    let plane = if sign > 0 { slice + 1 } else { slice };
+                    greedy_merge(&mut mask, width, height, |i, j, w, h, material| {
+                        let corner = |a: usize, b: usize| {
+                            let mut p = [0; 3];
+                            p[d] = plane;
+                            p[u] = lo[u] + a as i32;
+                            p[v] = lo[v] + b as i32;
+                            Pos::new(p[0], p[1], p[2])
+                        };
+                        let mut corners = [corner(i, j), corner(i + w, j), corner(i + w, j + h), corner(i, j + h)];
+                        if sign < 0 {
+                            corners.reverse();
+                        }
+                        let mut normal = [0; 3];
+                        normal[d] = sign;
+                        quads.push(Quad {corners, normal: Pos::new(normal[0], normal[1], normal[2]), material});
+                    });
+                }
+            }
+        }
+
+        world.release_chunks();
+        Ok(Mesh {quads})
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("obj") => self.write_obj(path),
+            Some("ply") => self.write_ply(path),
+            _ => Err(format!("can't tell the mesh format of {}, use .obj or .ply", path.display())),
+        }
+    }
+
+    // Wavefront OBJ, with the material colors in an MTL file next to it
+    pub fn write_obj(&self, path: &Path) -> Result<(), String> {
+        let mtl_path = path.with_extension("mtl");
+        let mtl_name = mtl_path.file_name().and_then(|n| n.to_str()).unwrap_or("materials.mtl");
+
+        let mut materials: Vec<MaterialID> = self.quads.iter().map(|q| q.material).collect();
+        materials.sort();
+        materials.dedup();
+
+        let mut mtl = String::new();
+        for &id in &materials {
+            let color = Material::from_id(id).color().sdl_format();
+            let _ = writeln!(mtl, "newmtl material_{}", id);
+            let _ = writeln!(mtl, "Kd {:.4} {:.4} {:.4}", color.r as f32 / 255.0, color.g as f32 / 255.0, color.b as f32 / 255.0);
+            let _ = writeln!(mtl, "d {:.4}\n", color.a as f32 / 255.0);
+        }
+
+        let mut obj = format!("mtllib {}\n", mtl_name);
+        for quad in &self.quads {
+            for c in &quad.corners {
+                let _ = writeln!(obj, "v {} {} {}", c.x, c.y, c.z);
+            }
+        }
+        const NORMALS: [(i32, i32, i32); 6] = [(1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)];
+        for (x, y, z) in NORMALS {
+            let _ = writeln!(obj, "vn {} {} {}", x, y, z);
+        }
+        for &id in &materials {
+            let _ = writeln!(obj, "usemtl material_{}", id);
+            for (index, quad) in self.quads.iter().enumerate().filter(|(_, q)| q.material == id) {
+                let n = &quad.normal;
+                let normal = NORMALS.iter().position(|&v| v == (n.x, n.y, n.z)).unwrap() + 1;
+                let first = index * 4 + 1;
+                let _ = writeln!(obj, "f {}//{n} {}//{n} {}//{n} {}//{n}", first, first + 1, first + 2, first + 3, n = normal);
+            }
+        }
+
+        fs::write(&mtl_path, mtl).map_err(|e| format!("can't write {}: {}", mtl_path.display(), e))?;
+        fs::write(path, obj).map_err(|e| format!("can't write {}: {}", path.display(), e))
+    }
+
+    // ASCII PLY with the material color on every vertex
+    pub fn write_ply(&self, path: &Path) -> Result<(), String> {
+        let mut ply = String::new();
+        let _ = writeln!(ply, "ply\nformat ascii 1.0");
+        let _ = writeln!(ply, "element vertex {}", self.quads.len() * 4);
+        let _ = writeln!(ply, "property float x\nproperty float y\nproperty float z");
+        let _ = writeln!(ply, "property uchar red\nproperty uchar green\nproperty uchar blue\nproperty uchar alpha");
+        let _ = writeln!(ply, "element face {}", self.quads.len());
+        let _ = writeln!(ply, "property list uchar int vertex_indices\nend_header");
+        for quad in &self.quads {
+            let color = Material::from_id(quad.material).color().sdl_format();
+            for c in &quad.corners {
+                let _ = writeln!(ply, "{} {} {} {} {} {} {}", c.x, c.y, c.z, color.r, color.g, color.b, color.a);
+            }
+        }
+        for index in 0..self.quads.len() {
+            let first = index * 4;
+            let _ = writeln!(ply, "4 {} {} {} {}", first, first + 1, first + 2, first + 3);
+        }
+        fs::write(path, ply).map_err(|e| format!("can't write {}: {}", path.display(), e))
+    }
+}
+
+
+// Covers the non zero cells of the mask with rectangles of equal values, growing each one first
+// along i and then along j. emit gets the corner, width, height and value of every rectangle
+fn greedy_merge(mask: &mut [MaterialID], width: usize, height: usize, mut emit: impl FnMut(usize, usize, usize, usize, MaterialID)) {
+    for j in 0..height {
+        let mut i = 0;
+        while i < width {
+            let material = mask[j * width + i];
+            if material == 0 {
+                i += 1;
+                continue;
+            }
+
+            let mut w = 1;
+            while i + w < width && mask[j * width + i + w] == material {
+                w += 1;
+            }
+            let mut h = 1;
+            while j + h < height && (0..w).all(|k| mask[(j + h) * width + i + k] == material) {
+                h += 1;
+            }
+
+            for row in j..j + h {
+                for cell in &mut mask[row * width + i..row * width + i + w] {
+                    *cell = 0;
+                }
+            }
+            emit(i, j, w, h, material);
+            i += w;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxels::WorldParams;
+
+    fn faces(mesh: &Mesh) -> Vec<([Pos; 4], Pos, MaterialID)> {
+        mesh.quads.iter().map(|quad| (quad.corners, quad.normal, quad.material)).collect()
+    }
+
+    #[test]
+    fn meshing_stays_in_the_memory_budget() {
+        let (min, max) = (Pos::new(-40, -20, -40), Pos::new(39, 19, 39));
+        let expected = Mesh::from_region(&mut World::new(WorldParams::default(), 5), min, max).unwrap();
+
+        // room for little more than the chunks around the camera
+        let mut world = World::new(WorldParams::new(8, 2, 64 * 1024).unwrap(), 5);
+        world.load_area();
+        let mesh = Mesh::from_region(&mut world, min, max).unwrap();
+        assert!(world.memory_usage() <= world.params().memory_budget);
+        assert!(!mesh.quads.is_empty());
+        assert_eq!(faces(&mesh), faces(&expected));
+    }
+}
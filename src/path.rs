@@ -0,0 +1,263 @@
+use std::fs;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::linalg::*;
+use crate::camera::Camera;
+
+
+
+// Camera paths for flythroughs. Positions between keyframes follow a Catmull-Rom spline through
+// all of them, orientations are slerped and the fov is interpolated linearly.
+// Playback moves along the spline at a fixed speed, using a table of distances sampled along it.
+// Segments shorter than a second at that speed, like turning or zooming in place, take a second.
+// Paths are saved as text, one keyframe per line:
+//   x y z qw qx qy qz fov
+const SAMPLES_PER_SEGMENT: usize = 32;
+const MIN_SEGMENT_SECONDS: f32 = 1.0;
+// where recorded keyframes go when no path file was given
+pub const DEFAULT_PATH_FILE: &str = "camera.path";
+
+
+#[derive(Debug, Copy, PartialEq, Clone)]
+pub struct Keyframe {
+    pub position: Vec3,
+    pub orientation: Quat,
+    pub fov: f32,
+}
+
+impl Keyframe {
+    pub fn of(camera: &Camera) -> Keyframe {
+        Keyframe {position: camera.position(), orientation: camera.orientation(), fov: camera.fov()}
+    }
+
+    pub fn apply(&self, camera: &mut Camera) {
+        camera.set_position(self.position);
+        camera.set_orientation(self.orientation);
+        camera.set_fov(self.fov);
+    }
+}
+
+
+#[derive(Clone, Default)]
+pub struct CameraPath {
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl CameraPath {
+    pub fn new() -> CameraPath {
+        CameraPath {keyframes: Vec::new()}
+    }
+
+    pub fn push(&mut self, keyframe: Keyframe) {
+        self.keyframes.push(keyframe);
+    }
+
+    pub fn len(&self) -> usize {
+        self.keyframes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keyframes.is_empty()
+    }
+
+    // t runs from 0 at the first keyframe to len - 1 at the last, passing keyframe i at t = i
+    pub fn sample(&self, t: f32) -> Keyframe {
+        let last = self.keyframes.len() - 1;
+        if last == 0 {
+            return self.keyframes[0];
+        }
+        let t = t.clamp(0.0, last as f32);
+        let i = (t.floor() as usize).min(last - 1);
+        let u = t - i as f32;
+
+        // the ends repeat the first and last keyframe
+        let at = |j: isize| self.keyframes[j.clamp(0, last as isize) as usize];
+        let (k0, k1, k2, k3) = (at(i as isize - 1), at(i as isize), at(i as isize + 1), at(i as isize + 2));
+        Keyframe {
+            position: catmull_rom(k0.position, k1.position, k2.position, k3.position, u),
+            orientation: k1.orientation.slerp(&k2.orientation, u),
+            fov: k1.fov + (k2.fov - k1.fov) * u,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut text = String::from("# x y z qw qx qy qz fov\n");
+        for k in &self.keyframes {
+            let (p, q) = (k.position, k.orientation);
+            let _ = writeln!(text, "{} {} {} {} {} {} {} {}", p.x, p.y, p.z, q.w, q.x, q.y, q.z, k.fov);
+        }
+        fs::write(path, text).map_err(|e| format!("can't write {}: {}", path.display(), e))
+    }
+
+    pub fn load(path: &Path) -> Result<CameraPath, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let mut camera_path = CameraPath::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values: Vec<f32> = line.split_whitespace().map(|v| v.parse()).collect::<Result<_, _>>()
+                .map_err(|e| format!("{} line {}: {}", path.display(), n + 1, e))?;
+            let [x, y, z, qw, qx, qy, qz, fov] = values[..] else {
+                return Err(format!("{} line {}: expected 8 numbers, found {}", path.display(), n + 1, values.len()));
+            };
+            camera_path.push(Keyframe {
+                position: Vec3::new(x, y, z),
+                orientation: Quat {w: qw, x: qx, y: qy, z: qz}.normalize(),
+                fov,
+            });
+        }
+        Ok(camera_path)
+    }
+}
+
+
+fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, u: f32) -> Vec3 {
+    let (u2, u3) = (u * u, u * u * u);
+    p0.scale(-u3 + 2.0 * u2 - u)
+        .add(&p1.scale(3.0 * u3 - 5.0 * u2 + 2.0))
+        .add(&p2.scale(-3.0 * u3 + 4.0 * u2 + u))
+        .add(&p3.scale(u3 - u2))
+        .scale(0.5)
+}
+
+
+// Walks a path at a fixed speed. Frames are counted so a playback doubles as a benchmark route
+pub struct Playback {
+    path: CameraPath,
+    speed: f32,
+    // distance along the spline at every SAMPLES_PER_SEGMENT-th of a segment
+    distances: Vec<f32>,
+    travelled: f32,
+    done: bool,
+    frames: u32,
+    started: Instant,
+}
+
+impl Playback {
+    pub fn new(path: CameraPath, speed: f32) -> Result<Playback, String> {
+        if path.is_empty() {
+            return Err("the camera path has no keyframes".to_string());
+        }
+        let mut distances = Vec::with_capacity((path.len() - 1) * SAMPLES_PER_SEGMENT + 1);
+        distances.push(0.0);
+        let min_length = speed * MIN_SEGMENT_SECONDS;
+        for segment in 0..path.len() - 1 {
+            let mut previous = path.sample(segment as f32).position;
+            let mut steps = Vec::with_capacity(SAMPLES_PER_SEGMENT);
+            for i in 1..=SAMPLES_PER_SEGMENT {
+                let position = path.sample(segment as f32 + i as f32 / SAMPLES_PER_SEGMENT as f32).position;
+                steps.push(position.sub(&previous).length());
+                previous = position;
+            }
+            // stretched so the orientation and fov still get time to change, evenly when it doesn't move at all
+            let length: f32 = steps.iter().sum();
+            if length < 1e-3 {
+                steps = vec![min_length / SAMPLES_PER_SEGMENT as f32; SAMPLES_PER_SEGMENT];
+            }
+            else if length < min_length {
+                steps.iter_mut().for_each(|step| *step *= min_length / length);
+            }
+            for step in steps {
+                distances.push(distances.last().unwrap() + step);
+            }
+        }
+        Ok(Playback {path, speed, distances, travelled: 0.0, done: false, frames: 0, started: Instant::now()})
+    }
+
+    pub fn length(&self) -> f32 {
+        *self.distances.last().unwrap()
+    }
+
+    // true once the last keyframe was shown
+    pub fn finished(&self) -> bool {
+        self.done
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    pub fn seconds(&self) -> f32 {
+        self.started.elapsed().as_secs_f32()
+    }
+
+    // the keyframe of this frame, then moves on by dt seconds. None after the end was shown
+    pub fn next_frame(&mut self, dt: f32) -> Option<Keyframe> {
+        if self.done {
+            return None;
+        }
+        let frame = self.at_distance(self.travelled);
+        self.frames += 1;
+        self.done = self.travelled >= self.length();
+        self.travelled = (self.travelled + self.speed * dt).min(self.length());
+        Some(frame)
+    }
+
+    fn at_distance(&self, distance: f32) -> Keyframe {
+        if self.distances.len() == 1 {
+            return self.path.sample(0.0);
+        }
+        let i = self.distances.partition_point(|&d| d <= distance).clamp(1, self.distances.len() - 1);
+        let (before, after) = (self.distances[i - 1], self.distances[i]);
+        let fraction = if after > before { ((distance - before) / (after - before)).min(1.0) } else { 0.0 };
+        self.path.sample((i - 1) as f32 / SAMPLES_PER_SEGMENT as f32 + fraction / SAMPLES_PER_SEGMENT as f32)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframe(x: f32, yaw: f32) -> Keyframe {
+        Keyframe {position: Vec3::new(x, 1.0, -2.0), orientation: Quat::from_axis_angle(&Y_AXIS, yaw), fov: 1.5}
+    }
+
+    fn frames(path: CameraPath, speed: f32, dt: f32) -> Vec<Keyframe> {
+        let mut playback = Playback::new(path, speed).unwrap();
+        std::iter::from_fn(|| playback.next_frame(dt)).collect()
+    }
+
+    #[test]
+    fn moves_at_a_fixed_speed() {
+        let path = CameraPath {keyframes: vec![keyframe(0.0, 0.0), keyframe(10.0, 0.0)]};
+        let frames = frames(path, 5.0, 0.1);
+        // 2 seconds of frames and the last keyframe
+        assert_eq!(frames.len(), 21);
+        for pair in frames.windows(2).take(19) {
+            assert!((pair[1].position.x - pair[0].position.x - 0.5).abs() < 0.01);
+        }
+        assert_eq!(frames.last().unwrap().position, Vec3::new(10.0, 1.0, -2.0));
+    }
+
+    #[test]
+    fn turning_in_place_takes_a_second_per_keyframe() {
+        let path = CameraPath {keyframes: vec![keyframe(3.0, 0.0), keyframe(3.0, 1.0), keyframe(3.0, 2.0)]};
+        let frames = frames(path, 5.0, 0.1);
+        assert_eq!(frames.len(), 21);
+        assert!(frames[10].orientation.dot(&keyframe(3.0, 1.0).orientation).abs() > 0.9999);
+        assert!(frames.last().unwrap().orientation.dot(&keyframe(3.0, 2.0).orientation).abs() > 0.9999);
+    }
+
+    #[test]
+    fn turning_between_moves_takes_a_second() {
+        let path = CameraPath {keyframes: vec![keyframe(0.0, 0.0), keyframe(10.0, 0.0), keyframe(10.0, 1.5), keyframe(20.0, 1.5)]};
+        let frames = frames(path, 5.0, 0.1);
+        // 2 seconds of moving, 1 of turning, 2 of moving and the last keyframe
+        assert_eq!(frames.len(), 51);
+        let (before, after) = (keyframe(10.0, 0.0).orientation, keyframe(10.0, 1.5).orientation);
+        let turning: Vec<&Keyframe> = frames.iter()
+            .filter(|frame| frame.orientation.dot(&before).abs() < 0.9999 && frame.orientation.dot(&after).abs() < 0.9999)
+            .collect();
+        assert_eq!(turning.len(), 9);
+        // the spline overshoots a little between the moves on either side
+        for frame in turning {
+            assert!((frame.position.x - 10.0).abs() < 0.5, "{:?}", frame.position);
+        }
+        assert!(frames[25].orientation.dot(&keyframe(10.0, 0.75).orientation).abs() > 0.9999);
+    }
+}
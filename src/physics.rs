@@ -0,0 +1,257 @@
+use crate::linalg::*;
+use crate::voxels::{World, Material, MaterialID, Materials};
+
+
+
+// Walking player: an axis aligned box with the camera at eye height, pulled down by gravity and
+// stopped by solid voxels. Movement is swept one axis at a time in steps shorter than a voxel,
+// so fast falls can't tunnel through the ground. Unloaded chunks don't collide
+const HALF_WIDTH: f32 = 0.3;
+const HEIGHT: f32 = 1.8;
+const EYE_HEIGHT: f32 = 1.6;
+const GRAVITY: f32 = 25.0;
+const JUMP_SPEED: f32 = 8.0;
+const MAX_FALL_SPEED: f32 = 50.0;
+// ledges up to this high are climbed without jumping
+const STEP_HEIGHT: f32 = 1.0;
+const SWEEP_STEP: f32 = 0.25;
+const EPSILON: f32 = 1e-3;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MoveMode {
+    Fly,
+    Walk,
+}
+
+
+pub struct Player {
+    pub mode: MoveMode,
+    // what gets placed
+    pub block: MaterialID,
+    vertical_speed: f32,
+    on_ground: bool,
+}
+
+impl Player {
+    pub fn new() -> Player {
+        Player {mode: MoveMode::Fly, block: Materials::Stone as MaterialID, vertical_speed: 0.0, on_ground: false}
+    }
+
+    pub fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            MoveMode::Fly => MoveMode::Walk,
+            MoveMode::Walk => MoveMode::Fly,
+        };
+        self.vertical_speed = 0.0;
+        self.on_ground = false;
+    }
+
+    pub fn on_ground(&self) -> bool {
+        self.on_ground
+    }
+
+    // moves the eye by the horizontal displacement walked this frame, plus gravity and jumping.
+    // Returns the new eye position
+    pub fn walk(&mut self, world: &World, eye: Vec3, walked: Vec3, jump: bool, dt: f32) -> Vec3 {
+        if jump && self.on_ground {
+            self.vertical_speed = JUMP_SPEED;
+        }
+        self.vertical_speed = (self.vertical_speed - GRAVITY * dt).max(-MAX_FALL_SPEED);
+
+        let mut feet = [eye.x, eye.y - EYE_HEIGHT, eye.z];
+        let could_step = self.on_ground;
+        for (axis, amount) in [(0, walked.x), (2, walked.z)] {
+            let mut moved = feet;
+            let blocked = sweep(world, &mut moved, axis, amount);
+            if blocked && could_step {
+                let mut raised = feet;
+                raised[1] += STEP_HEIGHT;
+                if !collides(world, raised) {
+                    sweep(world, &mut raised, axis, amount);
+                    if (raised[axis] - feet[axis]).abs() > (moved[axis] - feet[axis]).abs() {
+                        moved = raised;
+                    }
+                }
+            }
+            feet = moved;
+        }
+
+        let falling = self.vertical_speed <= 0.0;
+        let blocked = sweep(world, &mut feet, 1, self.vertical_speed * dt);
+        self.on_ground = blocked && falling;
+        if blocked {
+            self.vertical_speed = 0.0;
+        }
+
+        Vec3::new(feet[0], feet[1] + EYE_HEIGHT, feet[2])
+    }
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Player::new()
+    }
+}
+
+
+// moves the box along one axis until it hits something, and leaves it touching the obstacle.
+// Returns whether it was stopped
+fn sweep(world: &World, feet: &mut [f32; 3], axis: usize, amount: f32) -> bool {
+    if amount == 0.0 {
+        return false;
+    }
+    let steps = (amount.abs() / SWEEP_STEP).ceil();
+    let step = amount / steps;
+    for _ in 0..steps as usize {
+        let mut next = *feet;
+        next[axis] += step;
+        if !collides(world, next) {
+            *feet = next;
+            continue;
+        }
+
+        let (low, high) = extent(axis);
+        feet[axis] = if step > 0.0 {
+            // the voxel hit starts at the integer the far side of the box crossed
+            (next[axis] + high - EPSILON).floor() - high
+        }
+        else {
+            (next[axis] + low + EPSILON).floor() + 1.0 - low
+        };
+        return true;
+    }
+    false
+}
+
+// how far the box goes below and above feet on an axis
+fn extent(axis: usize) -> (f32, f32) {
+    match axis {
+        1 => (0.0, HEIGHT),
+        _ => (-HALF_WIDTH, HALF_WIDTH),
+    }
+}
+
+fn collides(world: &World, feet: [f32; 3]) -> bool {
+    // voxels the box only touches don't count
+    let range = |axis: usize| {
+        let (low, high) = extent(axis);
+        (feet[axis] + low + EPSILON).floor() as i32..=(feet[axis] + high - EPSILON).floor() as i32
+    };
+    for x in range(0) {
+        for y in range(1) {
+            for z in range(2) {
+                if world.voxel_at(Pos::new(x, y, z)).is_some_and(|m| Material::from_id(m).is_solid()) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+// whether the body of a player with its eye at eye takes up part of the voxel
+pub fn body_overlaps(eye: Vec3, voxel: Pos) -> bool {
+    let feet = [eye.x, eye.y - EYE_HEIGHT, eye.z];
+    let voxel = [voxel.x, voxel.y, voxel.z];
+    (0..3).all(|axis| {
+        let (low, high) = extent(axis);
+        feet[axis] + low + EPSILON < (voxel[axis] + 1) as f32 && feet[axis] + high - EPSILON > voxel[axis] as f32
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxels::{Chunk, WorldParams};
+
+    const DT: f32 = 1.0 / 60.0;
+
+    // empty chunks with a stone floor whose top is at y = 1, under x and z from 0 to 16
+    fn floor_world() -> Box<World> {
+        let mut world = World::new(WorldParams::default(), 0);
+        for x in -1..3 {
+            for y in -1..2 {
+                for z in -1..3 {
+                    world.load_chunk(Chunk::new(Pos::new(x, y, z), world.params().chunk_size));
+                }
+            }
+        }
+        for x in 0..16 {
+            for z in 0..16 {
+                world.set_voxel(Pos::new(x, 0, z), Materials::Stone as MaterialID);
+            }
+        }
+        world
+    }
+
+    fn wall(world: &mut World, x: i32, height: i32) {
+        for y in 1..=height {
+            for z in 0..16 {
+                world.set_voxel(Pos::new(x, y, z), Materials::Stone as MaterialID);
+            }
+        }
+    }
+
+    // a player standing on the floor, and its eye
+    fn standing(world: &World) -> (Player, Vec3) {
+        let mut player = Player::new();
+        let mut eye = Vec3::new(4.5, 1.0 + EYE_HEIGHT, 4.5);
+        eye = player.walk(world, eye, Vec3::new(0.0, 0.0, 0.0), false, DT);
+        assert!(player.on_ground());
+        (player, eye)
+    }
+
+    #[test]
+    fn falling_stops_on_the_floor() {
+        let world = floor_world();
+        let mut player = Player::new();
+        let mut eye = Vec3::new(4.5, 6.0, 4.5);
+        for _ in 0..120 {
+            eye = player.walk(&world, eye, Vec3::new(0.0, 0.0, 0.0), false, DT);
+        }
+        assert!(player.on_ground());
+        assert!((eye.y - (1.0 + EYE_HEIGHT)).abs() < 1e-4, "{}", eye.y);
+    }
+
+    #[test]
+    fn walking_climbs_a_one_voxel_step() {
+        let mut world = floor_world();
+        wall(&mut world, 6, 1);
+        let (mut player, mut eye) = standing(&world);
+        // onto the middle of the step, which is one voxel deep
+        for _ in 0..20 {
+            eye = player.walk(&world, eye, Vec3::new(0.1, 0.0, 0.0), false, DT);
+        }
+        assert!((eye.x - 6.5).abs() < 1e-4, "{}", eye.x);
+        assert!((eye.y - (2.0 + EYE_HEIGHT)).abs() < 1e-4, "{}", eye.y);
+    }
+
+    #[test]
+    fn walking_stops_at_a_two_voxel_wall() {
+        let mut world = floor_world();
+        wall(&mut world, 6, 2);
+        let (mut player, mut eye) = standing(&world);
+        for _ in 0..30 {
+            eye = player.walk(&world, eye, Vec3::new(0.1, 0.0, 0.0), false, DT);
+        }
+        assert!((eye.x - (6.0 - HALF_WIDTH)).abs() < 1e-4, "{}", eye.x);
+        assert!((eye.y - (1.0 + EYE_HEIGHT)).abs() < 1e-4, "{}", eye.y);
+    }
+
+    #[test]
+    fn jumping_needs_the_ground() {
+        let world = floor_world();
+        let (mut player, eye) = standing(&world);
+        let jumped = player.walk(&world, eye, Vec3::new(0.0, 0.0, 0.0), true, DT);
+        assert!(jumped.y > eye.y);
+        assert!(!player.on_ground());
+
+        // in the air, jumping again doesn't push up
+        let mut falling = Player::new();
+        let start = Vec3::new(4.5, 6.0, 4.5);
+        let after = falling.walk(&world, start, Vec3::new(0.0, 0.0, 0.0), true, DT);
+        assert!(after.y < start.y);
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::voxels::Color;
+
+
+
+// Rendered images, and writing them for video tools: numbered binary PPM images, or a single
+// uncompressed YUV4MPEG2 stream (4:4:4, BT.601 studio range) that ffmpeg and friends read as is
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    // row by row, starting at the top left
+    pub pixels: Vec<Color>,
+}
+
+impl Frame {
+    pub fn pixel(&self, col: u32, row: u32) -> Color {
+        self.pixels[(row * self.width + col) as usize]
+    }
+
+    pub fn rgb(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.pixels.len() * 3);
+        for color in &self.pixels {
+            let c = color.sdl_format();
+            bytes.extend_from_slice(&[c.r, c.g, c.b]);
+        }
+        bytes
+    }
+
+    pub fn write_ppm(&self, path: &Path) -> Result<(), String> {
+        let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.extend(self.rgb());
+        fs::write(path, data).map_err(|e| format!("can't write {}: {}", path.display(), e))
+    }
+}
+
+
+pub struct Y4mWriter {
+    out: BufWriter<File>,
+    width: u32,
+    height: u32,
+}
+
+impl Y4mWriter {
+    pub fn create(path: &Path, width: u32, height: u32, fps: u32) -> Result<Y4mWriter, String> {
+        let file = File::create(path).map_err(|e| format!("can't create {}: {}", path.display(), e))?;
+        let mut out = BufWriter::new(file);
+        writeln!(out, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444", width, height, fps)
+            .map_err(|e| format!("can't write {}: {}", path.display(), e))?;
+        Ok(Y4mWriter {out, width, height})
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), String> {
+        if frame.width != self.width || frame.height != self.height {
+            return Err(format!("frame is {}x{}, the video is {}x{}", frame.width, frame.height, self.width, self.height));
+        }
+        // the three planes one after the other
+        let rgb = frame.rgb();
+        let mut planes = vec![0; rgb.len()];
+        let size = frame.pixels.len();
+        for (i, p) in rgb.chunks(3).enumerate() {
+            let (r, g, b) = (p[0] as f32, p[1] as f32, p[2] as f32);
+            planes[i] = (16.0 + 0.257 * r + 0.504 * g + 0.098 * b).round() as u8;
+            planes[size + i] = (128.0 - 0.148 * r - 0.291 * g + 0.439 * b).round() as u8;
+            planes[2 * size + i] = (128.0 + 0.439 * r - 0.368 * g - 0.071 * b).round() as u8;
+        }
+        self.out.write_all(b"FRAME\n").and_then(|_| self.out.write_all(&planes))
+            .map_err(|e| format!("can't write video frame: {}", e))
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        self.out.flush().map_err(|e| format!("can't write video: {}", e))
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::linalg::*;
+use crate::input::{Action, FrameInput};
+use crate::path::Keyframe;
+
+
+
+// Recordings of what the player did, frame by frame, with the length of every frame.
+// Everything that moves the camera or edits the world only depends on these, the world seed, the
+// camera's start and the settings recorded with them, like the speeds and sensitivities, so
+// replaying them redoes the same session whatever the config says. Both sessions run in the freshly
+// generated world of the seed, without reading or writing the save directory or a world file.
+// The file is text, a header followed by one line per frame:
+//   raytracer input 1
+//   seed 42
+//   start x y z qw qx qy qz fov
+//   setting name value       (one line per setting)
+//   dt held look_x look_y wheel quit pressed,actions
+// with - for no pressed actions
+const HEADER: &str = "raytracer input 1";
+
+
+pub struct InputRecording {
+    pub seed: u64,
+    pub start: Keyframe,
+    // name and value of every recorded setting, as the config reads them
+    pub settings: Vec<(String, String)>,
+    pub frames: Vec<(f32, FrameInput)>,
+}
+
+impl InputRecording {
+    pub fn load(path: &Path) -> Result<InputRecording, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let mut lines = text.lines().enumerate().peekable();
+        let err = |n: usize, e: String| format!("{} line {}: {}", path.display(), n + 1, e);
+
+        match lines.next() {
+            Some((_, HEADER)) => {},
+            _ => return Err(format!("{} is not an input recording", path.display())),
+        }
+        let (n, line) = lines.next().ok_or(err(1, "missing seed".to_string()))?;
+        let seed = line.strip_prefix("seed ").and_then(|seed| seed.parse().ok())
+            .ok_or(err(n, format!("expected seed, found {:?}", line)))?;
+        let (n, line) = lines.next().ok_or(err(2, "missing start".to_string()))?;
+        let start = line.strip_prefix("start ").ok_or(String::new()).and_then(parse_keyframe)
+            .map_err(|_| err(n, format!("expected start x y z qw qx qy qz fov, found {:?}", line)))?;
+
+        let mut settings = Vec::new();
+        while let Some((n, setting)) = lines.next_if(|(_, line)| line.starts_with("setting ")) {
+            let (name, value) = setting["setting ".len()..].split_once(' ')
+                .ok_or(err(n, format!("expected setting name value, found {:?}", setting)))?;
+            settings.push((name.to_string(), value.to_string()));
+        }
+
+        let mut frames = Vec::new();
+        for (n, line) in lines {
+            frames.push(parse_frame(line).map_err(|e| err(n, e))?);
+        }
+        Ok(InputRecording {seed, start, settings, frames})
+    }
+}
+
+
+// Writes frames as they happen, so a crash still leaves the recording up to it
+pub struct InputRecorder {
+    out: BufWriter<File>,
+}
+
+impl InputRecorder {
+    pub fn create(path: &Path, seed: u64, start: Keyframe, settings: &[(String, String)]) -> Result<InputRecorder, String> {
+        let file = File::create(path).map_err(|e| format!("can't create {}: {}", path.display(), e))?;
+        let mut out = BufWriter::new(file);
+        let (p, q) = (start.position, start.orientation);
+        writeln!(out, "{}\nseed {}\nstart {} {} {} {} {} {} {} {}", HEADER, seed, p.x, p.y, p.z, q.w, q.x, q.y, q.z, start.fov)
+            .map_err(|e| format!("can't write {}: {}", path.display(), e))?;
+        for (name, value) in settings {
+            writeln!(out, "setting {} {}", name, value).map_err(|e| format!("can't write {}: {}", path.display(), e))?;
+        }
+        Ok(InputRecorder {out})
+    }
+
+    pub fn record(&mut self, dt: f32, frame: &FrameInput) -> Result<(), String> {
+        let pressed: Vec<&str> = frame.pressed.iter().map(|action| action.name()).collect();
+        let pressed = if pressed.is_empty() { "-".to_string() } else { pressed.join(",") };
+        // floats print with as many digits as it takes to read back the same value
+        writeln!(self.out, "{} {} {} {} {} {} {}", dt, frame.held, frame.look.0, frame.look.1, frame.wheel, frame.quit as u8, pressed)
+            .and_then(|_| self.out.flush())
+            .map_err(|e| format!("can't write input recording: {}", e))
+    }
+}
+
+
+fn parse_keyframe(text: &str) -> Result<Keyframe, String> {
+    let values: Vec<f32> = text.split_whitespace().map(|v| v.parse()).collect::<Result<_, _>>().map_err(|e| format!("{}", e))?;
+    let [x, y, z, qw, qx, qy, qz, fov] = values[..] else {
+        return Err(format!("expected 8 numbers, found {}", values.len()));
+    };
+    Ok(Keyframe {position: Vec3::new(x, y, z), orientation: Quat {w: qw, x: qx, y: qy, z: qz}, fov})
+}
+
+fn parse_frame(line: &str) -> Result<(f32, FrameInput), String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [dt, held, look_x, look_y, wheel, quit, pressed] = fields[..] else {
+        return Err(format!("expected 7 fields, found {}", fields.len()));
+    };
+    let number = |field: &str| field.parse::<i32>().map_err(|_| format!("invalid number {:?}", field));
+    let pressed = match pressed {
+        "-" => Vec::new(),
+        names => names.split(',').map(|name| Action::from_name(name).ok_or(format!("unknown action {:?}", name)))
+            .collect::<Result<_, _>>()?,
+    };
+    let frame = FrameInput {
+        pressed,
+        held: held.parse().map_err(|_| format!("invalid held actions {:?}", held))?,
+        look: (number(look_x)?, number(look_y)?),
+        wheel: number(wheel)?,
+        quit: quit == "1",
+    };
+    Ok((dt.parse().map_err(|_| format!("invalid frame time {:?}", dt))?, frame))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::config::Config;
+    use crate::voxels::{World, WorldParams};
+
+    // turns and flies the camera like the main loop does, with the settings of config
+    fn drive(config: &Config, start: Keyframe, frames: &[(f32, FrameInput)]) -> Camera {
+        let world = World::new(WorldParams::default(), 1);
+        let mut camera = Camera::new(world, Vec3::new(0.0, 0.0, 0.0), Z_AXIS, Y_AXIS, 1.5, config.pixels_per_unit);
+        camera.set_horizon_locked(config.horizon_lock);
+        start.apply(&mut camera);
+        for (dt, frame) in frames {
+            camera.rotate_yaw(frame.look.0 as f32 * config.mouse_sensitivity);
+            camera.rotate_pitch(-frame.look.1 as f32 * config.mouse_sensitivity);
+            camera.rotate_roll(frame.wheel as f32 * config.scroll_sensitivity);
+            camera.move_rel_to_facing(Vec3::new(0.0, 0.0, 1.0).scale(dt * config.camera_speed));
+        }
+        camera
+    }
+
+    #[test]
+    fn recordings_replay_with_their_settings() {
+        let path = std::env::temp_dir().join(format!("raytracer-replay-{}.txt", std::process::id()));
+        let mut config = Config::default();
+        config.set("camera_speed", "3.7").unwrap();
+        config.set("mouse_sensitivity", "0.35").unwrap();
+        config.set("pixels_per_unit", "70").unwrap();
+        config.set("horizon_lock", "true").unwrap();
+        let start = Keyframe {position: Vec3::new(1.5, 20.25, -3.0), orientation: Quat::from_axis_angle(&Y_AXIS, 0.3), fov: 1.2};
+        let frames: Vec<(f32, FrameInput)> = (0..20)
+            .map(|i| (1.0 / 60.0 + i as f32 * 1e-4, FrameInput {look: (i * 3 - 20, 7 - i), wheel: i % 3 - 1, ..FrameInput::default()}))
+            .collect();
+
+        let mut recorder = InputRecorder::create(&path, 42, start, &config.recorded_settings()).unwrap();
+        for (dt, frame) in &frames {
+            recorder.record(*dt, frame).unwrap();
+        }
+        drop(recorder);
+        let recording = InputRecording::load(&path).unwrap();
+        assert_eq!(recording.seed, 42);
+        assert_eq!(recording.settings, config.recorded_settings());
+        assert_eq!(recording.frames, frames);
+
+        // a config that differs gets the recorded settings back
+        let mut replayed = Config::default();
+        for (name, value) in &recording.settings {
+            replayed.set(name, value).unwrap();
+        }
+        let (recorded, replayed) = (drive(&config, start, &frames), drive(&replayed, recording.start, &recording.frames));
+        assert_eq!(replayed.position(), recorded.position());
+        assert_eq!(replayed.orientation(), recorded.orientation());
+        assert_eq!(replayed.fov(), recorded.fov());
+    }
+}
@@ -0,0 +1,398 @@
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::linalg::*;
+use crate::voxels::{Chunk, Material, MaterialID, WorldParams};
+use crate::compression::{rle_encode, rle_decode};
+
+
+
+// World save format, all integers little endian:
+//
+//   magic            8 bytes  "RTWORLD\0"
+//   version          u16
+//   chunk size       u32
+//   render distance  u32
+//   memory budget    u64
+//   seed             u64
+//   center chunk     3 x i32
+//   material count   u16
+//   materials        count x (r, g, b, a) u8
+//   chunk count      u32
+//   chunks           count x
+//       coords       3 x i32
+//       length       u32
+//       metadata len u32
+//       crc32        u32      checksum of the voxels and the metadata
+//       voxels       length bytes, run length encoded
+//       metadata     metadata len bytes, run length encoded like the voxels
+const MAGIC: &[u8; 8] = b"RTWORLD\0";
+const FORMAT_VERSION: u16 = 1;
+// coords, lengths and checksum of a chunk without voxels
+const MIN_CHUNK_RECORD: usize = 24;
+
+
+// Writes a save one chunk at a time, so the chunks don't all have to be in memory together.
+// Nothing replaces the file at path until finish
+pub struct SaveWriter {
+    out: BufWriter<fs::File>,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    // where the chunk count goes, it's only known once every chunk is written
+    count_at: u64,
+    count: u32,
+}
+
+impl SaveWriter {
+    pub fn create(path: &Path, params: WorldParams, seed: u64, center: Pos) -> Result<SaveWriter, String> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&(params.chunk_size as u32).to_le_bytes());
+        out.extend_from_slice(&(params.render_distance as u32).to_le_bytes());
+        out.extend_from_slice(&(params.memory_budget as u64).to_le_bytes());
+        out.extend_from_slice(&seed.to_le_bytes());
+        write_pos(&mut out, center);
+
+        out.extend_from_slice(&256u16.to_le_bytes());
+        for id in 0..=MaterialID::MAX {
+            let color = Material::from_id(id).color().sdl_format();
+            out.extend_from_slice(&[color.r, color.g, color.b, color.a]);
+        }
+        let count_at = out.len() as u64;
+        out.extend_from_slice(&0u32.to_le_bytes());
+
+        let tmp_path = path.with_extension("tmp");
+        let file = fs::File::create(&tmp_path).map_err(|e| format!("can't write {}: {}", tmp_path.display(), e))?;
+        let mut writer = SaveWriter {out: BufWriter::new(file), path: path.to_path_buf(), tmp_path, count_at, count: 0};
+        writer.write_bytes(&out)?;
+        Ok(writer)
+    }
+
+    pub fn write_chunk(&mut self, chunk: &Chunk) -> Result<(), String> {
+        let voxels = rle_encode(&chunk.to_bytes());
+        let metadata = rle_encode(&chunk.metadata_bytes());
+        let mut out = Vec::with_capacity(24 + voxels.len() + metadata.len());
+        write_pos(&mut out, chunk.coords());
+        out.extend_from_slice(&(voxels.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+        out.extend_from_slice(&crc32(&[voxels.as_slice(), &metadata].concat()).to_le_bytes());
+        out.extend_from_slice(&voxels);
+        out.extend_from_slice(&metadata);
+        self.write_bytes(&out)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        let count = self.count.to_le_bytes();
+        self.out.seek(SeekFrom::Start(self.count_at))
+            .and_then(|_| self.out.write_all(&count))
+            .and_then(|_| self.out.flush())
+            .map_err(|e| format!("can't write {}: {}", self.tmp_path.display(), e))?;
+        fs::rename(&self.tmp_path, &self.path).map_err(|e| format!("can't replace {}: {}", self.path.display(), e))
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.out.write_all(bytes).map_err(|e| format!("can't write {}: {}", self.tmp_path.display(), e))
+    }
+}
+
+
+// Reads a save one chunk at a time, the header is read when opening it
+pub struct SaveReader {
+    pub params: WorldParams,
+    pub seed: u64,
+    pub center: Pos,
+    path: PathBuf,
+    reader: Reader,
+    remap: Vec<MaterialID>,
+    chunks_left: usize,
+}
+
+impl SaveReader {
+    pub fn open(path: &Path) -> Result<SaveReader, String> {
+        let file = fs::File::open(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let len = file.metadata().map_err(|e| format!("can't read {}: {}", path.display(), e))?.len() as usize;
+        let reader = Reader {input: BufReader::new(file), len, at: 0};
+        SaveReader::read_header(reader, path).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn read_header(mut reader: Reader, path: &Path) -> Result<SaveReader, String> {
+        if reader.take(MAGIC.len(), "magic")? != MAGIC {
+            return Err("not a world save".to_string());
+        }
+        let version = reader.u16("format version")?;
+        if version != FORMAT_VERSION {
+            return Err(format!("format version {} isn't the supported version {}", version, FORMAT_VERSION));
+        }
+        let params = read_params(&mut reader)?;
+        let seed = reader.u64("seed")?;
+        let center = reader.pos("center chunk")?;
+        let remap = read_materials(&mut reader)?;
+
+        let chunks_left = reader.u32("chunk count")? as usize;
+        // a corrupt count would otherwise only be noticed at the end of the file, after loading the chunks before it
+        if chunks_left > reader.remaining() / MIN_CHUNK_RECORD {
+            return Err(format!("chunk count {} doesn't fit in the {} bytes left in the file", chunks_left, reader.remaining()));
+        }
+        Ok(SaveReader {params, seed, center, path: path.to_path_buf(), reader, remap, chunks_left})
+    }
+
+    // None once every chunk has been read
+    pub fn next_chunk(&mut self) -> Result<Option<Box<Chunk>>, String> {
+        if self.chunks_left == 0 {
+            return Ok(None);
+        }
+        self.chunks_left -= 1;
+        self.read_chunk().map(Some).map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+
+    fn read_chunk(&mut self) -> Result<Box<Chunk>, String> {
+        let reader = &mut self.reader;
+        let params = self.params;
+        let coords = reader.pos("chunk coordinates")?;
+        let length = reader.u32("chunk length")? as usize;
+        let metadata_length = reader.u32("chunk metadata length")? as usize;
+        let checksum = reader.u32("chunk checksum")?;
+        let payload = reader.take(length, "chunk payload")?;
+        let metadata = reader.take(metadata_length, "chunk metadata")?;
+        if crc32(&[payload.as_slice(), &metadata].concat()) != checksum {
+            return Err(format!("chunk {:?} is corrupt, its checksum doesn't match", coords));
+        }
+
+        let voxels: Vec<MaterialID> = rle_decode(&payload, params.chunk_volume())
+            .map_err(|e| format!("chunk {:?} is corrupt: {}", coords, e))?
+            .into_iter()
+            .map(|id| self.remap[id as usize])
+            .collect();
+        let mut chunk = Chunk::from_bytes(coords, params.chunk_size, &voxels)?;
+        let metadata = rle_decode(&metadata, params.chunk_volume()).map_err(|e| format!("chunk {:?} is corrupt: {}", coords, e))?;
+        chunk.set_metadata_bytes(&metadata)?;
+        Ok(chunk)
+    }
+}
+
+fn read_params(reader: &mut Reader) -> Result<WorldParams, String> {
+    let chunk_size = reader.u32("chunk size")? as usize;
+    let render_distance = reader.u32("render distance")? as usize;
+    let memory_budget = reader.u64("memory budget")? as usize;
+    WorldParams::new(chunk_size, render_distance, memory_budget)
+}
+
+// maps the material ids of the save onto the current table, matching them by color.
+// Ids whose color isn't in the table anymore are kept as they are
+fn read_materials(reader: &mut Reader) -> Result<Vec<MaterialID>, String> {
+    let count = reader.u16("material count")? as usize;
+    let mut remap: Vec<MaterialID> = (0..=MaterialID::MAX).collect();
+    for (id, target) in remap.iter_mut().enumerate().take(count) {
+        let rgba = reader.take(4, "material table")?;
+        let current = Material::from_id(id as MaterialID).color().sdl_format();
+        if [current.r, current.g, current.b, current.a] == rgba[..] {
+            continue;
+        }
+        let found = (0..=MaterialID::MAX).find(|&other| {
+            let color = Material::from_id(other).color().sdl_format();
+            [color.r, color.g, color.b, color.a] == rgba[..]
+        });
+        if let Some(other) = found {
+            *target = other;
+        }
+    }
+    // materials past the 256 we know of can't be represented
+    reader.take(count.saturating_sub(remap.len()) * 4, "material table")?;
+    Ok(remap)
+}
+
+
+fn write_pos(out: &mut Vec<u8>, pos: Pos) {
+    out.extend_from_slice(&pos.x.to_le_bytes());
+    out.extend_from_slice(&pos.y.to_le_bytes());
+    out.extend_from_slice(&pos.z.to_le_bytes());
+}
+
+
+struct Reader {
+    input: BufReader<fs::File>,
+    len: usize,
+    at: usize,
+}
+
+impl Reader {
+    fn remaining(&self) -> usize {
+        self.len - self.at
+    }
+
+    // checks the length against the file before allocating, a corrupt length could ask for any amount of memory
+    fn take(&mut self, len: usize, what: &str) -> Result<Vec<u8>, String> {
+        if len > self.remaining() {
+            return Err(format!("file ends at byte {} while reading the {}", self.len, what));
+        }
+        let mut bytes = vec![0; len];
+        self.input.read_exact(&mut bytes).map_err(|e| format!("can't read the {}: {}", what, e))?;
+        self.at += len;
+        Ok(bytes)
+    }
+    fn u16(&mut self, what: &str) -> Result<u16, String> {
+        let b = self.take(2, what)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self, what: &str) -> Result<u32, String> {
+        let b = self.take(4, what)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self, what: &str) -> Result<u64, String> {
+        let b = self.take(8, what)?;
+        Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    fn pos(&mut self, what: &str) -> Result<Pos, String> {
+        let x = self.u32(what)? as i32;
+        let y = self.u32(what)? as i32;
+        let z = self.u32(what)? as i32;
+        Ok(Pos::new(x, y, z))
+    }
+}
+
+
+// CRC-32 (IEEE), the checksum used by zip and png
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxels::{Materials, World};
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("raytracer-{}-{}.sav", name, std::process::id()))
+    }
+
+    fn write(path: &Path, seed: u64, center: Pos, chunks: &[&Chunk]) -> Result<(), String> {
+        let mut writer = SaveWriter::create(path, WorldParams::default(), seed, center)?;
+        for chunk in chunks {
+            writer.write_chunk(chunk)?;
+        }
+        writer.finish()
+    }
+
+    fn read(path: &Path) -> Result<(SaveReader, Vec<Chunk>), String> {
+        let mut reader = SaveReader::open(path)?;
+        let mut chunks = Vec::new();
+        while let Some(chunk) = reader.next_chunk()? {
+            chunks.push(*chunk);
+        }
+        Ok((reader, chunks))
+    }
+
+    fn chunk_with_stone(coords: Pos) -> Box<Chunk> {
+        let mut chunk = Chunk::new(coords, 8);
+        chunk.set_voxel(Vec3::new(1.0, 2.0, 3.0), Materials::Stone as MaterialID);
+        chunk
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let path = temp_path("savefile-round-trip");
+        let mut water = Chunk::new(Pos::new(0, 0, 0), 8);
+        water.set_voxel(Vec3::new(4.0, 4.0, 4.0), Materials::Water as MaterialID);
+        water.set_metadata(Vec3::new(4.0, 4.0, 4.0), 5);
+        let chunks = [chunk_with_stone(Pos::new(-1, 0, 2)), water];
+        let refs: Vec<&Chunk> = chunks.iter().map(|chunk| chunk.as_ref()).collect();
+        write(&path, 42, Pos::new(1, 2, 3), &refs).unwrap();
+
+        let (reader, read_chunks) = read(&path).unwrap();
+        assert_eq!((reader.seed, reader.center), (42, Pos::new(1, 2, 3)));
+        assert_eq!(read_chunks.len(), 2);
+        for (read, written) in read_chunks.iter().zip(&chunks) {
+            assert_eq!(read.coords(), written.coords());
+            assert_eq!(read.to_bytes(), written.to_bytes());
+            assert_eq!(read.metadata_bytes(), written.metadata_bytes());
+        }
+    }
+
+    #[test]
+    fn corrupt_chunk_count_is_an_error() {
+        let path = temp_path("savefile-chunk-count");
+        let chunk = chunk_with_stone(Pos::new(0, 0, 0));
+        write(&path, 0, Pos::new(0, 0, 0), &[]).unwrap();
+        // the count is the last field of a save without chunks
+        let count_at = fs::metadata(&path).unwrap().len() as usize - 4;
+        write(&path, 0, Pos::new(0, 0, 0), &[&chunk]).unwrap();
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[count_at..count_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        let err = read(&path).err().unwrap();
+        assert!(err.contains("chunk count"), "{}", err);
+
+        bytes[count_at..count_at + 4].copy_from_slice(&2u32.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        assert!(read(&path).is_err());
+    }
+
+    #[test]
+    fn corrupt_payload_is_an_error() {
+        let path = temp_path("savefile-payload");
+        write(&path, 0, Pos::new(0, 0, 0), &[&chunk_with_stone(Pos::new(0, 0, 0))]).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 2;
+        bytes[last] ^= 0xFF;
+        fs::write(&path, &bytes).unwrap();
+        let err = read(&path).err().unwrap();
+        assert!(err.contains("checksum"), "{}", err);
+    }
+
+    #[test]
+    fn only_edited_chunks_are_saved() {
+        let path = temp_path("savefile-edited");
+        let mut world = World::new(WorldParams::default(), 7);
+        for x in 0..3 {
+            world.ensure_loaded(Pos::new(x, 0, 0));
+        }
+        world.set_voxel(Pos::new(9, 1, 1), Materials::Gold as MaterialID);
+        world.save(&path).unwrap();
+
+        let (_, chunks) = read(&path).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].coords(), Pos::new(1, 0, 0));
+    }
+
+    #[test]
+    fn loading_with_a_store_keeps_far_chunks_out_of_memory() {
+        let path = temp_path("savefile-far-chunks");
+        let dir = std::env::temp_dir().join(format!("raytracer-savefile-far-chunks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut world = World::new(WorldParams::default(), 7);
+        for x in [0, 20, 40] {
+            world.ensure_loaded(Pos::new(x, 0, 0));
+            world.set_voxel(Pos::new(x * 8 + 1, 1, 1), Materials::Gold as MaterialID);
+        }
+        world.save(&path).unwrap();
+
+        let loaded = World::load(&path, Some(&dir)).unwrap();
+        assert!(loaded.get_chunk(Pos::new(0, 0, 0)).is_some());
+        assert!(loaded.get_chunk(Pos::new(20, 0, 0)).is_none());
+        assert!(loaded.get_chunk(Pos::new(40, 0, 0)).is_none());
+
+        // the far chunks come back from the store when saving again
+        loaded.save(&path).unwrap();
+        let (_, chunks) = read(&path).unwrap();
+        let mut coords: Vec<i32> = chunks.iter().map(|chunk| chunk.coords().x).collect();
+        coords.sort();
+        assert_eq!(coords, vec![0, 20, 40]);
+    }
+}
@@ -0,0 +1,144 @@
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::linalg::*;
+use crate::voxels::{World, MaterialID};
+
+
+
+// World simulation, run at a fixed rate no matter how fast frames are rendered.
+// Every tick runs each registered system once, then hands a few random voxels of every loaded
+// chunk to the systems' random_tick, so slow per voxel rules don't have to visit every voxel.
+// Which voxels get picked only depends on the seed and the tick number, so replaying the same
+// ticks on the same world gives the same result
+pub const TICKS_PER_SECOND: u32 = 20;
+pub const TICK_SECONDS: f32 = 1.0 / TICKS_PER_SECOND as f32;
+// after a long frame the simulation slows down rather than trying to catch up forever
+const MAX_TICKS_PER_ADVANCE: u32 = 10;
+const RANDOM_TICKS_PER_CHUNK: u32 = 3;
+
+
+pub struct TickContext {
+    // number of the current tick, starting at 0
+    pub tick: u64,
+    // chunks with voxels changed since the previous tick, sorted
+    pub changed_chunks: Vec<Pos>,
+}
+
+
+impl TickContext {
+    // the changed chunks and their neighbours, anything that reacts to its surroundings may have to move there
+    pub fn active_chunks(&self) -> Vec<Pos> {
+        let mut active = Vec::with_capacity(self.changed_chunks.len() * 7);
+        for coords in &self.changed_chunks {
+            active.push(*coords);
+            for (dx, dy, dz) in [(1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)] {
+                active.push(coords.add(&Pos::new(dx, dy, dz)));
+            }
+        }
+        active.sort_by_key(|c| (c.x, c.y, c.z));
+        active.dedup();
+        active
+    }
+
+    // every voxel of the active chunks whose material passes the test, bottom up so falling things
+    // move as a whole column in one tick instead of one voxel per tick
+    pub fn active_voxels(&self, world: &World, matches: impl Fn(MaterialID) -> bool) -> Vec<Pos> {
+        let size = world.params().chunk_size as i32;
+        let mut found = Vec::new();
+        for coords in self.active_chunks() {
+            let Some(chunk) = world.get_chunk(coords) else { continue; };
+            if chunk.is_empty() {
+                continue;
+            }
+            for x in 0..size {
+                for y in 0..size {
+                    for z in 0..size {
+                        if matches(chunk.get_voxel(Vec3::new(x as f32, y as f32, z as f32))) {
+                            found.push(coords.scale(size).add(&Pos::new(x, y, z)));
+                        }
+                    }
+                }
+            }
+        }
+        found.sort_by_key(|p| (p.y, p.z, p.x));
+        found
+    }
+}
+
+
+pub trait TickSystem {
+    fn name(&self) -> &str;
+
+    fn tick(&mut self, world: &mut World, ctx: &TickContext);
+
+    fn random_tick(&mut self, _world: &mut World, _pos: Pos, _material: MaterialID, _ctx: &TickContext) {}
+}
+
+
+pub struct Simulation {
+    systems: Vec<Box<dyn TickSystem>>,
+    tick: u64,
+    // simulated seconds owed by the frames that haven't been ticked yet
+    accumulator: f32,
+    seed: u64,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Simulation {
+        Simulation {
+            systems: Vec::new(),
+            tick: 0,
+            accumulator: 0.0,
+            seed,
+        }
+    }
+
+    // systems tick in the order they were registered
+    pub fn register(&mut self, system: impl TickSystem + 'static) {
+        self.systems.push(Box::new(system));
+    }
+
+    pub fn tick_count(&self) -> u64 {
+        self.tick
+    }
+
+    // runs as many ticks as fit in the time passed since the last call, returns how many ran
+    pub fn advance(&mut self, world: &mut World, dt: f32) -> u32 {
+        self.accumulator += dt;
+        let mut ticks = 0;
+        while self.accumulator >= TICK_SECONDS && ticks < MAX_TICKS_PER_ADVANCE {
+            self.step(world);
+            self.accumulator -= TICK_SECONDS;
+            ticks += 1;
+        }
+        if ticks == MAX_TICKS_PER_ADVANCE {
+            self.accumulator = 0.0;
+        }
+        ticks
+    }
+
+    pub fn step(&mut self, world: &mut World) {
+        let ctx = TickContext {tick: self.tick, changed_chunks: world.take_changed_chunks()};
+        for system in self.systems.iter_mut() {
+            system.tick(world, &ctx);
+        }
+
+        let mut rng = StdRng::seed_from_u64(self.seed ^ self.tick.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let size = world.params().chunk_size as i32;
+        let mut chunks: Vec<Pos> = world.loaded_chunks().map(|chunk| chunk.coords()).collect();
+        chunks.sort_by_key(|c| (c.x, c.y, c.z));
+        for coords in chunks {
+            for _ in 0..RANDOM_TICKS_PER_CHUNK {
+                let offset = Pos::new(rng.gen_range(0..size), rng.gen_range(0..size), rng.gen_range(0..size));
+                let pos = coords.scale(size).add(&offset);
+                let Some(material) = world.voxel_at(pos) else { continue; };
+                for system in self.systems.iter_mut() {
+                    system.random_tick(world, pos, material, &ctx);
+                }
+            }
+        }
+
+        self.tick += 1;
+    }
+}
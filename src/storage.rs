@@ -0,0 +1,308 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::linalg::*;
+use crate::voxels::Chunk;
+use crate::compression::{rle_encode, rle_decode};
+
+
+
+// Region files group REGION_SIZE^3 chunks in a single file:
+//   magic "RGN1", chunk size (u32), seed of the world (u64)
+//   table of REGION_VOLUME entries, each (offset u32, length u32), a length of 0 means the chunk is not stored
+//   chunk payloads, each
+//       voxel length u32
+//       voxels       voxel length bytes, run length encoded
+//       metadata     the rest, run length encoded like the voxels
+// All integers are little endian. Stored chunks only make sense on top of the terrain they were
+// edited in, so regions of another seed are refused
+const REGION_MAGIC: &[u8; 4] = b"RGN1";
+const REGION_SIZE: i32 = 8;
+const REGION_VOLUME: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+const TABLE_LEN: usize = REGION_VOLUME * 8;
+const HEADER_LEN: usize = 16 + TABLE_LEN;
+
+
+pub struct RegionStore {
+    dir: PathBuf,
+    chunk_size: usize,
+    seed: u64,
+}
+
+impl RegionStore {
+    // fails right away when the directory holds the regions of another world
+    pub fn open(dir: &Path, chunk_size: usize, seed: u64) -> Result<RegionStore, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("can't create save directory {}: {}", dir.display(), e))?;
+        let store = RegionStore {dir: dir.to_path_buf(), chunk_size, seed};
+        let entries = fs::read_dir(dir).map_err(|e| format!("can't list {}: {}", dir.display(), e))?;
+        let first = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path())
+            .find(|path| RegionStore::parse_region_name(path).is_some());
+        if let Some(path) = first {
+            let mut file = fs::File::open(&path).map_err(|e| format!("can't read region {}: {}", path.display(), e))?;
+            store.read_header(&path, &mut file)?;
+        }
+        Ok(store)
+    }
+
+    fn region_path(&self, region: Pos) -> PathBuf {
+        self.dir.join(format!("r.{}.{}.{}.region", region.x, region.y, region.z))
+    }
+
+    fn slot(coords: Pos) -> usize {
+        let local = coords.modulo(REGION_SIZE);
+        ((local.z * REGION_SIZE + local.y) * REGION_SIZE + local.x) as usize
+    }
+
+    // where every slot's payload is in the file, (offset, length) with a length of 0 for no chunk
+    fn read_header(&self, path: &Path, data: &mut impl Read) -> Result<Vec<(usize, usize)>, String> {
+        let not_region = format!("{} is not a region file", path.display());
+        let mut start = [0; 16];
+        data.read_exact(&mut start).map_err(|_| not_region.clone())?;
+        if start[0..4] != REGION_MAGIC[..] {
+            return Err(not_region);
+        }
+        let chunk_size = read_u32(&start, 4) as usize;
+        if chunk_size != self.chunk_size {
+            return Err(format!("{} holds chunks of size {}, the world uses {}", path.display(), chunk_size, self.chunk_size));
+        }
+        let seed = u64::from_le_bytes(start[8..16].try_into().unwrap());
+        if seed != self.seed {
+            return Err(format!("{} belongs to the world with seed {}, this one has seed {}, use another save_dir",
+                path.display(), seed, self.seed));
+        }
+
+        let mut bytes = vec![0; TABLE_LEN];
+        data.read_exact(&mut bytes).map_err(|_| not_region.clone())?;
+        Ok((0..REGION_VOLUME)
+            .map(|slot| (read_u32(&bytes, slot * 8) as usize, read_u32(&bytes, 4 + slot * 8) as usize))
+            .collect())
+    }
+
+    fn decode(&self, path: &Path, coords: Pos, payload: &[u8]) -> Result<Box<Chunk>, String> {
+        let corrupt = |e: String| format!("{}: chunk {:?} is corrupt: {}", path.display(), coords, e);
+        let volume = self.chunk_size.pow(3);
+        let voxel_len = payload.get(0..4).map(|b| read_u32(b, 0) as usize).ok_or(corrupt("no voxel length".to_string()))?;
+        let voxels = payload.get(4..4 + voxel_len).ok_or(corrupt(format!("voxel length {} past its end", voxel_len)))?;
+        let metadata = &payload[4 + voxel_len..];
+
+        let mut chunk = Chunk::from_bytes(coords, self.chunk_size, &rle_decode(voxels, volume).map_err(corrupt)?)?;
+        chunk.set_metadata_bytes(&rle_decode(metadata, volume).map_err(corrupt)?)?;
+        Ok(chunk)
+    }
+
+    // the payload of every chunk stored in a region, indexed by slot
+    fn read_region(&self, path: &Path) -> Result<Vec<Option<Vec<u8>>>, String> {
+        let mut payloads = vec![None; REGION_VOLUME];
+        if !path.exists() {
+            return Ok(payloads);
+        }
+
+        let bytes = fs::read(path).map_err(|e| format!("can't read region {}: {}", path.display(), e))?;
+        let table = self.read_header(path, &mut &bytes[..])?;
+        for (payload, &(offset, length)) in payloads.iter_mut().zip(&table) {
+            if length == 0 {
+                continue;
+            }
+            if offset + length > bytes.len() {
+                return Err(format!("{} is truncated", path.display()));
+            }
+            *payload = Some(bytes[offset..offset + length].to_vec());
+        }
+        Ok(payloads)
+    }
+
+    fn write_region(&self, path: &Path, payloads: &[Option<Vec<u8>>]) -> Result<(), String> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(REGION_MAGIC);
+        header.extend_from_slice(&(self.chunk_size as u32).to_le_bytes());
+        header.extend_from_slice(&self.seed.to_le_bytes());
+
+        let mut body = Vec::new();
+        for payload in payloads {
+            let (offset, length) = match payload {
+                Some(data) => {
+                    let offset = HEADER_LEN + body.len();
+                    body.extend_from_slice(data);
+                    (offset, data.len())
+                },
+                None => (0, 0),
+            };
+            header.extend_from_slice(&(offset as u32).to_le_bytes());
+            header.extend_from_slice(&(length as u32).to_le_bytes());
+        }
+
+        // written next to the region and renamed over it, so a crash never leaves half a file behind
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path).map_err(|e| format!("can't write region {}: {}", tmp_path.display(), e))?;
+        file.write_all(&header).and_then(|_| file.write_all(&body)).and_then(|_| file.sync_all())
+            .map_err(|e| format!("can't write region {}: {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, path).map_err(|e| format!("can't replace region {}: {}", path.display(), e))
+    }
+
+    // only reads the header and the one payload, not the rest of the region
+    pub fn load_chunk(&self, coords: Pos) -> Result<Option<Box<Chunk>>, String> {
+        let path = self.region_path(coords.div(REGION_SIZE));
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut file = fs::File::open(&path).map_err(|e| format!("can't read region {}: {}", path.display(), e))?;
+        let table = self.read_header(&path, &mut file)?;
+        let (offset, length) = table[RegionStore::slot(coords)];
+        if length == 0 {
+            return Ok(None);
+        }
+
+        let mut data = vec![0; length];
+        file.seek(SeekFrom::Start(offset as u64)).and_then(|_| file.read_exact(&mut data))
+            .map_err(|_| format!("{} is truncated", path.display()))?;
+        self.decode(&path, coords, &data).map(Some)
+    }
+
+    // every region with a file in the store, sorted so they are visited in the same order every time
+    pub fn regions(&self) -> Result<Vec<Pos>, String> {
+        let entries = fs::read_dir(&self.dir).map_err(|e| format!("can't list {}: {}", self.dir.display(), e))?;
+        let mut regions = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if let Some(region) = RegionStore::parse_region_name(&path) {
+                regions.push(region);
+            }
+        }
+        regions.sort_by_key(|r| (r.x, r.y, r.z));
+        Ok(regions)
+    }
+
+    // the chunks stored in one region, reading its file once
+    pub fn region_chunks(&self, region: Pos) -> Result<Vec<Box<Chunk>>, String> {
+        let path = self.region_path(region);
+        let payloads = self.read_region(&path)?;
+        let mut chunks = Vec::new();
+        for (slot, payload) in payloads.iter().enumerate() {
+            let Some(payload) = payload else { continue; };
+            let slot = slot as i32;
+            let local = Pos::new(slot % REGION_SIZE, slot / REGION_SIZE % REGION_SIZE, slot / (REGION_SIZE * REGION_SIZE));
+            let coords = region.scale(REGION_SIZE).add(&local);
+            chunks.push(self.decode(&path, coords, payload)?);
+        }
+        Ok(chunks)
+    }
+
+    fn parse_region_name(path: &Path) -> Option<Pos> {
+        let name = path.file_name()?.to_str()?;
+        let coords = name.strip_prefix("r.")?.strip_suffix(".region")?;
+        let mut parts = coords.split('.').map(|part| part.parse::<i32>());
+        let (x, y, z) = (parts.next()?.ok()?, parts.next()?.ok()?, parts.next()?.ok()?);
+        Some(Pos::new(x, y, z))
+    }
+
+    // rewrites each region the chunks fall in once, however many of them it gets
+    pub fn save_chunks(&self, chunks: &[&Chunk]) -> Result<(), String> {
+        let mut regions: Vec<(Pos, Vec<&Chunk>)> = Vec::new();
+        for chunk in chunks {
+            let region = chunk.coords().div(REGION_SIZE);
+            match regions.iter_mut().find(|(r, _)| *r == region) {
+                Some((_, list)) => list.push(chunk),
+                None => regions.push((region, vec![chunk])),
+            }
+        }
+
+        for (region, list) in regions {
+            let path = self.region_path(region);
+            let mut payloads = self.read_region(&path)?;
+            for chunk in list {
+                payloads[RegionStore::slot(chunk.coords())] = Some(encode(chunk));
+            }
+            self.write_region(&path, &payloads)?;
+        }
+        Ok(())
+    }
+}
+
+
+fn encode(chunk: &Chunk) -> Vec<u8> {
+    let voxels = rle_encode(&chunk.to_bytes());
+    let mut payload = (voxels.len() as u32).to_le_bytes().to_vec();
+    payload.extend(voxels);
+    payload.extend(rle_encode(&chunk.metadata_bytes()));
+    payload
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxels::{MaterialID, Materials};
+
+    fn store(name: &str) -> RegionStore {
+        let dir = std::env::temp_dir().join(format!("raytracer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        RegionStore::open(&dir, 8, 0).unwrap()
+    }
+
+    fn chunk_with(coords: Pos, material: Materials) -> Box<Chunk> {
+        let mut chunk = Chunk::new(coords, 8);
+        chunk.set_voxel(Vec3::new(1.0, 2.0, 3.0), material as MaterialID);
+        chunk
+    }
+
+    #[test]
+    fn chunks_of_a_region_load_one_at_a_time() {
+        let store = store("storage-load");
+        let stone = chunk_with(Pos::new(-1, 0, 0), Materials::Stone);
+        let sand = chunk_with(Pos::new(-8, -8, -8), Materials::Sand);
+        store.save_chunks(&[&stone, &sand]).unwrap();
+        // a second save keeps what the region already had
+        let water = chunk_with(Pos::new(-2, 0, 0), Materials::Water);
+        store.save_chunks(&[&water]).unwrap();
+
+        for chunk in [&stone, &sand, &water] {
+            let loaded = store.load_chunk(chunk.coords()).unwrap().unwrap();
+            assert_eq!(loaded.to_bytes(), chunk.to_bytes());
+        }
+        assert!(store.load_chunk(Pos::new(-3, 0, 0)).unwrap().is_none());
+        assert!(store.load_chunk(Pos::new(0, 0, 0)).unwrap().is_none());
+        assert_eq!(store.regions().unwrap(), vec![Pos::new(-1, -1, -1), Pos::new(-1, 0, 0)]);
+        assert_eq!(store.region_chunks(Pos::new(-1, 0, 0)).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn metadata_is_stored_with_the_voxels() {
+        let store = store("storage-metadata");
+        let mut chunk = chunk_with(Pos::new(3, -1, 0), Materials::Water);
+        chunk.set_metadata(Vec3::new(1.0, 2.0, 3.0), 3);
+        store.save_chunks(&[&chunk]).unwrap();
+
+        let loaded = store.load_chunk(chunk.coords()).unwrap().unwrap();
+        assert_eq!(loaded.get_metadata(Vec3::new(1.0, 2.0, 3.0)), 3);
+        assert_eq!(loaded.metadata_bytes(), chunk.metadata_bytes());
+        assert_eq!(store.region_chunks(Pos::new(0, -1, 0)).unwrap()[0].metadata_bytes(), chunk.metadata_bytes());
+    }
+
+    #[test]
+    fn regions_of_another_seed_are_refused() {
+        let store = store("storage-seed");
+        let chunk = chunk_with(Pos::new(0, 0, 0), Materials::Stone);
+        store.save_chunks(&[&chunk]).unwrap();
+        let err = RegionStore::open(&store.dir, 8, 1).err().unwrap();
+        assert!(err.contains("seed 0"), "{}", err);
+        assert!(RegionStore::open(&store.dir, 8, 0).is_ok());
+    }
+
+    #[test]
+    fn truncated_regions_are_errors() {
+        let store = store("storage-truncated");
+        let chunk = chunk_with(Pos::new(0, 0, 0), Materials::Stone);
+        store.save_chunks(&[&chunk]).unwrap();
+        let path = store.region_path(Pos::new(0, 0, 0));
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(store.load_chunk(chunk.coords()).is_err());
+        fs::write(&path, &bytes[..100]).unwrap();
+        assert!(store.load_chunk(chunk.coords()).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use rand::Rng;
+
+use crate::linalg::*;
+use crate::voxels::{Chunk, MaterialID, Materials};
+use crate::generation::TerrainGenerator;
+
+
+
+// Structures span several voxels and can cross chunk borders. They are planned per feature cell,
+// a CELL_SIZE x CELL_SIZE column of the world, from an rng of the seed and the cell alone, so
+// every chunk can work out all the structures that reach into it from its neighbouring cells
+// and stamp its own part of them, no matter which chunks were generated before
+const CELL_SIZE: i32 = 16;
+const STRUCTURE_STREAM: u64 = 1;
+// attempts at placing a structure in every cell, the ones on unsuitable ground are dropped
+const ATTEMPTS_PER_CELL: u32 = 3;
+// how far any structure reaches sideways from its origin
+const MAX_REACH: i32 = 3;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StructureKind {
+    Tree,
+    Boulder,
+    Hut,
+}
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct Structure {
+    pub kind: StructureKind,
+    // the first voxel above the ground
+    pub origin: Pos,
+    // picks between the variations of a kind, like the height of a tree
+    pub variant: u32,
+}
+
+impl Structure {
+    // huts clear out whatever is in their way, the rest only grows into air
+    fn overwrites(&self) -> bool {
+        self.kind == StructureKind::Hut
+    }
+
+    // voxels in world coordinates, later ones win over earlier ones
+    pub fn voxels(&self) -> Vec<(Pos, MaterialID)> {
+        let mut voxels = Vec::new();
+        let mut put = |x: i32, y: i32, z: i32, material: Materials| {
+            voxels.push((self.origin.add(&Pos::new(x, y, z)), material as MaterialID));
+        };
+
+        match self.kind {
+            StructureKind::Tree => {
+                let trunk = 4 + (self.variant % 3) as i32;
+                for y in 0..trunk {
+                    put(0, y, 0, Materials::Wood);
+                }
+                for x in -2..=2 {
+                    for y in -2..=2 {
+                        for z in -2..=2 {
+                            if x * x + y * y + z * z <= 5 {
+                                put(x, trunk + y, z, Materials::Leaves);
+                            }
+                        }
+                    }
+                }
+            },
+
+            StructureKind::Boulder => {
+                // sunk halfway into the ground
+                let radius = 1 + (self.variant % 2) as i32;
+                for x in -radius..=radius {
+                    for y in -radius..=radius {
+                        for z in -radius..=radius {
+                            if x * x + y * y + z * z <= radius * radius + 1 {
+                                put(x, y, z, Materials::Stone);
+                            }
+                        }
+                    }
+                }
+            },
+
+            StructureKind::Hut => {
+                const HALF: i32 = 2;
+                const WALL_HEIGHT: i32 = 3;
+                // the door faces one of the four sides
+                let door = (self.variant % 4) as usize;
+                for x in -HALF..=HALF {
+                    for z in -HALF..=HALF {
+                        put(x, -1, z, Materials::Wood);
+                        let edge = x.abs() == HALF || z.abs() == HALF;
+                        let doorway = [(0, HALF), (0, -HALF), (HALF, 0), (-HALF, 0)][door] == (x, z);
+                        for y in 0..WALL_HEIGHT {
+                            let wall = edge && !(doorway && y < 2);
+                            put(x, y, z, if wall { Materials::Wood } else { Materials::Air });
+                        }
+                        put(x, WALL_HEIGHT, z, Materials::Wood);
+                        if x.abs() < HALF && z.abs() < HALF {
+                            put(x, WALL_HEIGHT + 1, z, Materials::Wood);
+                        }
+                    }
+                }
+            },
+        }
+        voxels
+    }
+}
+
+
+// every structure planned in a cell, in a fixed order
+pub fn structures_in_cell(generator: &TerrainGenerator, cell_x: i32, cell_z: i32) -> Vec<Structure> {
+    let mut rng = generator.rng_at(Pos::new(cell_x, 0, cell_z), STRUCTURE_STREAM);
+    let mut structures = Vec::new();
+    for _ in 0..ATTEMPTS_PER_CELL {
+        // every attempt draws the same numbers whether it's kept or not, so a dropped attempt doesn't
+        // change the ones after it
+        let x = cell_x * CELL_SIZE + rng.gen_range(0..CELL_SIZE);
+        let z = cell_z * CELL_SIZE + rng.gen_range(0..CELL_SIZE);
+        let roll = rng.gen_range(0..100);
+        let variant = rng.gen::<u32>();
+
+        let kind = match roll {
+            0..=59 => StructureKind::Tree,
+            60..=84 => StructureKind::Boulder,
+            85..=89 => StructureKind::Hut,
+            _ => continue,
+        };
+        let (ground, material) = generator.surface_at(x, z);
+        let suitable = match kind {
+            StructureKind::Tree => material == Materials::Grass as MaterialID,
+            StructureKind::Boulder => material == Materials::Grass as MaterialID || material == Materials::Sand as MaterialID,
+            // on grass that is flat enough under all four corners
+            StructureKind::Hut => material == Materials::Grass as MaterialID && [(-2, -2), (-2, 2), (2, -2), (2, 2)].iter()
+                .all(|&(dx, dz)| (generator.surface_at(x + dx, z + dz).0 - ground).abs() <= 1),
+        };
+        if suitable {
+            structures.push(Structure {kind, origin: Pos::new(x, ground + 1, z), variant});
+        }
+    }
+    structures
+}
+
+// stamps the parts of every structure that fall inside the chunk
+pub fn decorate(generator: &TerrainGenerator, chunk: &mut Chunk) {
+    let size = chunk.size() as i32;
+    let min = chunk.coords().scale(size);
+    let max = min.add(&Pos::new(size - 1, size - 1, size - 1));
+    let first = Pos::new(min.x - MAX_REACH, 0, min.z - MAX_REACH).div(CELL_SIZE);
+    let last = Pos::new(max.x + MAX_REACH, 0, max.z + MAX_REACH).div(CELL_SIZE);
+
+    for cell_x in first.x..=last.x {
+        for cell_z in first.z..=last.z {
+            for structure in structures_in_cell(generator, cell_x, cell_z) {
+                let o = structure.origin;
+                if o.x + MAX_REACH < min.x || o.x - MAX_REACH > max.x || o.z + MAX_REACH < min.z || o.z - MAX_REACH > max.z {
+                    continue;
+                }
+                for (pos, material) in structure.voxels() {
+                    let inside = (min.x..=max.x).contains(&pos.x) && (min.y..=max.y).contains(&pos.y) && (min.z..=max.z).contains(&pos.z);
+                    if !inside {
+                        continue;
+                    }
+                    let local = pos.sub(&min).vec3();
+                    if structure.overwrites() || chunk.get_voxel(local) == Materials::Air as MaterialID {
+                        chunk.set_voxel(local, material);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,693 @@
+use std::fs;
+use std::path::Path;
+
+use crate::linalg::*;
+use crate::voxels::{World, Material, MaterialID, Materials};
+
+
+
+// MagicaVoxel .vox files: "VOX " and a version, followed by a tree of chunks
+//   id (4 bytes), content size (i32), children size (i32), content, children
+// MAIN holds every other chunk. Each model is a SIZE chunk followed by its XYZI chunk,
+// RGBA holds the palette and the nTRN/nGRP/nSHP nodes form the scene graph placing the models.
+// Vox is z up, the world is y up
+const VOX_MAGIC: &[u8; 4] = b"VOX ";
+const VOX_VERSION: i32 = 150;
+const MAX_MODEL_SIZE: i32 = 256;
+
+
+pub struct VoxModel {
+    pub size: Pos,
+    // x, y, z and palette index of every voxel, in vox coordinates
+    pub voxels: Vec<(u8, u8, u8, u8)>,
+}
+
+
+// Integer rotation and translation, as stored in nTRN nodes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoxTransform {
+    pub rotation: [[i32; 3]; 3],
+    pub translation: Pos,
+}
+
+impl VoxTransform {
+    pub fn identity() -> VoxTransform {
+        VoxTransform {rotation: [[1, 0, 0], [0, 1, 0], [0, 0, 1]], translation: Pos::new(0, 0, 0)}
+    }
+
+    // the rotation byte keeps the column of the non zero entry of the first two rows and the sign of each row
+    pub fn from_rotation_byte(byte: u8) -> Option<VoxTransform> {
+        let first = (byte & 3) as usize;
+        let second = ((byte >> 2) & 3) as usize;
+        if first > 2 || second > 2 || first == second {
+            return None;
+        }
+        let third = 3 - first - second;
+        let mut rotation = [[0; 3]; 3];
+        for (row, column) in [first, second, third].into_iter().enumerate() {
+            let negative = byte & (1 << (4 + row)) != 0;
+            rotation[row][column] = if negative { -1 } else { 1 };
+        }
+        Some(VoxTransform {rotation, translation: Pos::new(0, 0, 0)})
+    }
+
+    pub fn rotate(&self, p: Pos) -> Pos {
+        let r = &self.rotation;
+        Pos::new(
+            r[0][0] * p.x + r[0][1] * p.y + r[0][2] * p.z,
+            r[1][0] * p.x + r[1][1] * p.y + r[1][2] * p.z,
+            r[2][0] * p.x + r[2][1] * p.y + r[2][2] * p.z
+        )
+    }
+
+    pub fn apply(&self, p: Pos) -> Pos {
+        self.rotate(p).add(&self.translation)
+    }
+
+    // self applied after child
+    pub fn then(&self, child: &VoxTransform) -> VoxTransform {
+        let mut rotation = [[0; 3]; 3];
+        for (i, row) in rotation.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..3).map(|k| self.rotation[i][k] * child.rotation[k][j]).sum();
+            }
+        }
+        VoxTransform {rotation, translation: self.apply(child.translation)}
+    }
+}
+
+
+enum VoxNode {
+    Transform {child: i32, transform: VoxTransform},
+    Group {children: Vec<i32>},
+    Shape {models: Vec<usize>},
+}
+
+
+pub struct VoxScene {
+    pub models: Vec<VoxModel>,
+    pub palette: [[u8; 4]; 256],
+    // models placed by the scene graph, or every model at the origin in files without one
+    pub instances: Vec<(usize, VoxTransform)>,
+    // the scene graph places models by their center instead of their corner
+    centered: bool,
+}
+
+impl VoxScene {
+    pub fn load(path: &Path) -> Result<VoxScene, String> {
+        let bytes = fs::read(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        VoxScene::parse(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<VoxScene, String> {
+        let mut reader = VoxReader {bytes, at: 0};
+        if reader.take(4, "magic")? != VOX_MAGIC {
+            return Err("not a MagicaVoxel file".to_string());
+        }
+        let _version = reader.i32("version")?;
+
+        let (id, content, children) = reader.chunk()?;
+        if id != b"MAIN" || !content.is_empty() {
+            return Err("MAIN chunk missing".to_string());
+        }
+
+        let mut models = Vec::new();
+        let mut size = None;
+        let mut palette = default_palette();
+        let mut nodes: Vec<(i32, VoxNode)> = Vec::new();
+
+        let mut reader = VoxReader {bytes: children, at: 0};
+        while !reader.done() {
+            let (id, content, _) = reader.chunk()?;
+            let mut c = VoxReader {bytes: content, at: 0};
+            match id {
+                b"SIZE" => size = Some(Pos::new(c.i32("size")?, c.i32("size")?, c.i32("size")?)),
+                b"XYZI" => {
+                    let size = size.take().ok_or("XYZI chunk without a SIZE chunk before it")?;
+                    let count = c.i32("voxel count")?.max(0) as usize;
+                    let data = c.take(count * 4, "voxels")?;
+                    let voxels = data.chunks(4).map(|v| (v[0], v[1], v[2], v[3])).collect();
+                    models.push(VoxModel {size, voxels});
+                },
+                b"RGBA" => {
+                    let data = c.take(256 * 4, "palette")?;
+                    // palette index i uses the color stored at i - 1
+                    for i in 0..255 {
+                        palette[i + 1] = [data[i * 4], data[i * 4 + 1], data[i * 4 + 2], data[i * 4 + 3]];
+                    }
+                },
+                b"nTRN" => {
+                    let node = c.i32("node id")?;
+                    c.dict()?;
+                    let child = c.i32("child id")?;
+                    let _reserved = c.i32("reserved id")?;
+                    let _layer = c.i32("layer id")?;
+                    let frames = c.i32("frame count")?;
+                    let mut transform = VoxTransform::identity();
+                    // only the first frame, animations aren't supported
+                    if frames > 0 {
+                        for (key, value) in c.dict()? {
+                            match key.as_str() {
+                                "_r" => {
+                                    let rotation = value.parse().ok().and_then(VoxTransform::from_rotation_byte)
+                                        .ok_or(format!("bad rotation {:?}", value))?;
+                                    transform.rotation = rotation.rotation;
+                                },
+                                "_t" => {
+                                    let t: Vec<i32> = value.split_whitespace().filter_map(|n| n.parse().ok()).collect();
+                                    if t.len() != 3 {
+                                        return Err(format!("bad translation {:?}", value));
+                                    }
+                                    transform.translation = Pos::new(t[0], t[1], t[2]);
+                                },
+                                _ => {},
+                            }
+                        }
+                    }
+                    nodes.push((node, VoxNode::Transform {child, transform}));
+                },
+                b"nGRP" => {
+                    let node = c.i32("node id")?;
+                    c.dict()?;
+                    let count = c.i32("child count")?.max(0);
+                    let children = (0..count).map(|_| c.i32("child id")).collect::<Result<_, _>>()?;
+                    nodes.push((node, VoxNode::Group {children}));
+                },
+                b"nSHP" => {
+                    let node = c.i32("node id")?;
+                    c.dict()?;
+                    let count = c.i32("model count")?.max(0);
+                    let mut shape_models = Vec::new();
+                    for _ in 0..count {
+                        shape_models.push(c.i32("model id")?.max(0) as usize);
+                        c.dict()?;
+                    }
+                    nodes.push((node, VoxNode::Shape {models: shape_models}));
+                },
+                _ => {}, // materials, layers, cameras...
+            }
+        }
+
+        let mut instances = Vec::new();
+        let centered = !nodes.is_empty();
+        if !centered {
+            instances = (0..models.len()).map(|i| (i, VoxTransform::identity())).collect();
+        }
+        else {
+            collect_instances(&nodes, 0, VoxTransform::identity(), &mut instances, 0)?;
+        }
+        if let Some((model, _)) = instances.iter().find(|(model, _)| *model >= models.len()) {
+            return Err(format!("scene graph places model {} but the file has {}", model, models.len()));
+        }
+
+        Ok(VoxScene {models, palette, instances, centered})
+    }
+
+    // the voxels of the world between min and max (inclusive), split in models of up to 256^3.
+    // Palette index i is material i, so exporting and importing again gives back the same materials
+    pub fn from_region(world: &mut World, min: Pos, max: Pos) -> Result<VoxScene, String> {
+        if min.x > max.x || min.y > max.y || min.z > max.z {
+            return Err(format!("region {:?} to {:?} is empty", min, max));
+        }
+
+        let mut palette = [[0; 4]; 256];
+        for (id, entry) in palette.iter_mut().enumerate().skip(1) {
+            let color = Material::from_id(id as MaterialID).color().sdl_format();
+            *entry = [color.r, color.g, color.b, color.a];
+        }
+
+        // world y up to vox z up, the inverse of the rotation used when importing
+        let to_vox = |p: Pos| Pos::new(p.x, -p.z, p.y);
+        let vox_min = to_vox(Pos::new(min.x, min.y, max.z));
+        let vox_size = to_vox(Pos::new(max.x, max.y, min.z)).sub(&vox_min).add(&Pos::new(1, 1, 1));
+        let tiles = vox_size.add(&Pos::new(MAX_MODEL_SIZE - 1, MAX_MODEL_SIZE - 1, MAX_MODEL_SIZE - 1)).div(MAX_MODEL_SIZE);
+        let chunk_size = world.params().chunk_size as i32;
+
+        let mut models = Vec::new();
+        let mut instances = Vec::new();
+        for tx in 0..tiles.x {
+            for ty in 0..tiles.y {
+                for tz in 0..tiles.z {
+                    let tile_origin = Pos::new(tx, ty, tz).scale(MAX_MODEL_SIZE);
+                    let size = Pos::new(
+                        (vox_size.x - tile_origin.x).min(MAX_MODEL_SIZE), 
+                        (vox_size.y - tile_origin.y).min(MAX_MODEL_SIZE), 
+                        (vox_size.z - tile_origin.z).min(MAX_MODEL_SIZE)
+                    );
+
+                    let mut voxels = Vec::new();
+                    for x in 0..size.x {
+                        // the slab of chunks before this one is done with
+                        let world_x = vox_min.x + tile_origin.x + x;
+                        if world_x.rem_euclid(chunk_size) == 0 {
+                            world.release_chunks();
+                        }
+                        for y in 0..size.y {
+                            for z in 0..size.z {
+                                let v = vox_min.add(&tile_origin).add(&Pos::new(x, y, z));
+                                let p = Pos::new(v.x, v.z, -v.y);
+                                world.ensure_loaded(world.split_pos(p).0);
+                                match world.voxel_at(p) {
+                                    Some(material) if material != Materials::Air as MaterialID => {
+                                        voxels.push((x as u8, y as u8, z as u8, material));
+                                    },
+                                    _ => {},
+                                }
+                            }
+                        }
+                    }
+                    if voxels.is_empty() {
+                        continue;
+                    }
+
+                    let transform = VoxTransform {
+                        rotation: VoxTransform::identity().rotation,
+                        translation: vox_min.add(&tile_origin).add(&size.div(2)),
+                    };
+                    instances.push((models.len(), transform));
+                    models.push(VoxModel {size, voxels});
+                }
+            }
+        }
+
+        world.release_chunks();
+        Ok(VoxScene {models, palette, instances, centered: true})
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_bytes()).map_err(|e| format!("can't write {}: {}", path.display(), e))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut children = Vec::new();
+        for model in &self.models {
+            let mut size = Vec::new();
+            for v in [model.size.x, model.size.y, model.size.z] {
+                size.extend_from_slice(&v.to_le_bytes());
+            }
+            write_chunk(&mut children, b"SIZE", &size);
+
+            let mut xyzi = (model.voxels.len() as i32).to_le_bytes().to_vec();
+            for &(x, y, z, color) in &model.voxels {
+                xyzi.extend_from_slice(&[x, y, z, color]);
+            }
+            write_chunk(&mut children, b"XYZI", &xyzi);
+        }
+
+        // root transform (0) -> group (1) -> a transform and a shape for each instance
+        let mut root = Vec::new();
+        write_i32s(&mut root, &[0]);
+        write_dict(&mut root, &[]);
+        write_i32s(&mut root, &[1, -1, -1, 1]);
+        write_dict(&mut root, &[]);
+        write_chunk(&mut children, b"nTRN", &root);
+
+        let mut group = Vec::new();
+        write_i32s(&mut group, &[1]);
+        write_dict(&mut group, &[]);
+        write_i32s(&mut group, &[self.instances.len() as i32]);
+        for i in 0..self.instances.len() {
+            write_i32s(&mut group, &[2 + 2 * i as i32]);
+        }
+        write_chunk(&mut children, b"nGRP", &group);
+
+        for (i, (model, transform)) in self.instances.iter().enumerate() {
+            let node = 2 + 2 * i as i32;
+            let t = transform.translation;
+            let mut trn = Vec::new();
+            write_i32s(&mut trn, &[node]);
+            write_dict(&mut trn, &[]);
+            write_i32s(&mut trn, &[node + 1, -1, 0, 1]);
+            write_dict(&mut trn, &[("_t", &format!("{} {} {}", t.x, t.y, t.z))]);
+            write_chunk(&mut children, b"nTRN", &trn);
+
+            let mut shp = Vec::new();
+            write_i32s(&mut shp, &[node + 1]);
+            write_dict(&mut shp, &[]);
+            write_i32s(&mut shp, &[1, *model as i32]);
+            write_dict(&mut shp, &[]);
+            write_chunk(&mut children, b"nSHP", &shp);
+        }
+
+        let mut rgba = Vec::with_capacity(256 * 4);
+        for i in 0..256 {
+            rgba.extend_from_slice(&self.palette[(i + 1) % 256]);
+        }
+        write_chunk(&mut children, b"RGBA", &rgba);
+
+        let mut out = VOX_MAGIC.to_vec();
+        out.extend_from_slice(&VOX_VERSION.to_le_bytes());
+        out.extend_from_slice(b"MAIN");
+        out.extend_from_slice(&0i32.to_le_bytes());
+        out.extend_from_slice(&(children.len() as i32).to_le_bytes());
+        out.extend_from_slice(&children);
+        out
+    }
+
+    // every voxel of every placed model, in world axes (y up), with its palette index
+    pub fn voxels(&self) -> Vec<(Pos, u8)> {
+        // vox z up to world y up, a rotation so models don't get mirrored
+        let to_world = |p: Pos| Pos::new(p.x, p.z, -p.y);
+
+        let mut out = Vec::new();
+        for (model, transform) in &self.instances {
+            let model = &self.models[*model];
+            let pivot = if self.centered { model.size.div(2) } else { Pos::new(0, 0, 0) };
+            for &(x, y, z, color) in &model.voxels {
+                let local = Pos::new(x as i32, y as i32, z as i32).sub(&pivot);
+                out.push((to_world(transform.apply(local)), color));
+            }
+        }
+        out
+    }
+
+    // palette index to the material with the closest color
+    pub fn material_map(&self) -> [MaterialID; 256] {
+        let mut map = [Materials::Air as MaterialID; 256];
+        for (index, target) in map.iter_mut().enumerate().skip(1) {
+            let [r, g, b, _] = self.palette[index];
+            let distance = |id: MaterialID| {
+                let color = Material::from_id(id).color().sdl_format();
+                let (dr, dg, db) = (color.r as i32 - r as i32, color.g as i32 - g as i32, color.b as i32 - b as i32);
+                dr * dr + dg * dg + db * db
+            };
+            *target = (1..=MaterialID::MAX)
+                .filter(|&id| Material::from_id(id).color().sdl_format().a != 0)
+                .min_by_key(|&id| distance(id))
+                .unwrap_or(Materials::Stone as MaterialID);
+        }
+        map
+    }
+
+    // writes the scene into the world with the minimum corner of its bounding box at pos, returning the voxels written
+    pub fn stamp(&self, world: &mut World, pos: Pos) -> usize {
+        let voxels = self.voxels();
+        let Some(min) = voxels.iter().map(|(p, _)| *p).reduce(|a, b| Pos::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z))) else {
+            return 0;
+        };
+        let offset = pos.sub(&min);
+        let map = self.material_map();
+
+        // a column of chunks at a time, each one only needs to be in memory once and the ones done
+        // with are evicted together
+        let mut voxels: Vec<(Pos, Pos, u8)> = voxels.into_iter()
+            .map(|(p, color)| {
+                let target = p.add(&offset);
+                (world.split_pos(target).0, target, color)
+            })
+            .collect();
+        voxels.sort_by_key(|(chunk, _, _)| (chunk.x, chunk.z, chunk.y));
+
+        let mut written = 0;
+        let mut column = None;
+        for (chunk, target, color) in voxels {
+            if column != Some((chunk.x, chunk.z)) {
+                world.release_chunks();
+                column = Some((chunk.x, chunk.z));
+            }
+            world.ensure_loaded(chunk);
+            written += world.set_voxel(target, map[color as usize]) as usize;
+        }
+        world.release_chunks();
+        written
+    }
+}
+
+
+fn collect_instances(nodes: &[(i32, VoxNode)], id: i32, parent: VoxTransform, out: &mut Vec<(usize, VoxTransform)>, depth: usize) -> Result<(), String> {
+    if depth > 64 {
+        return Err("scene graph is too deep or has a cycle".to_string());
+    }
+    let node = nodes.iter().find(|(node, _)| *node == id).map(|(_, node)| node)
+        .ok_or(format!("scene graph references missing node {}", id))?;
+    match node {
+        VoxNode::Transform {child, transform} => collect_instances(nodes, *child, parent.then(transform), out, depth + 1)?,
+        VoxNode::Group {children} => {
+            for child in children {
+                collect_instances(nodes, *child, parent, out, depth + 1)?;
+            }
+        },
+        VoxNode::Shape {models} => out.extend(models.iter().map(|model| (*model, parent))),
+    }
+    Ok(())
+}
+
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(content.len() as i32).to_le_bytes());
+    out.extend_from_slice(&0i32.to_le_bytes());
+    out.extend_from_slice(content);
+}
+
+fn write_i32s(out: &mut Vec<u8>, values: &[i32]) {
+    for v in values {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+fn write_dict(out: &mut Vec<u8>, entries: &[(&str, &str)]) {
+    write_i32s(out, &[entries.len() as i32]);
+    for (key, value) in entries {
+        for text in [key, value] {
+            write_i32s(out, &[text.len() as i32]);
+            out.extend_from_slice(text.as_bytes());
+        }
+    }
+}
+
+
+// The palette MagicaVoxel uses for files without an RGBA chunk:
+// a 6 level color cube without black, followed by red, green, blue and gray ramps
+pub fn default_palette() -> [[u8; 4]; 256] {
+    const LEVELS: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut palette = [[0; 4]; 256];
+    let mut i = 1;
+    for r in LEVELS {
+        for g in LEVELS {
+            for b in LEVELS {
+                if r == 0 && g == 0 && b == 0 {
+                    continue;
+                }
+                palette[i] = [r, g, b, 0xff];
+                i += 1;
+            }
+        }
+    }
+    for channel in 0..4 {
+        for v in RAMP {
+            palette[i] = match channel {
+                0 => [v, 0, 0, 0xff],
+                1 => [0, v, 0, 0xff],
+                2 => [0, 0, v, 0xff],
+                _ => [v, v, v, 0xff],
+            };
+            i += 1;
+        }
+    }
+    palette
+}
+
+
+// id, content and children of a chunk
+type RawChunk<'a> = (&'a [u8], &'a [u8], &'a [u8]);
+
+struct VoxReader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> VoxReader<'a> {
+    fn done(&self) -> bool {
+        self.at >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize, what: &str) -> Result<&'a [u8], String> {
+        if self.at + len > self.bytes.len() {
+            return Err(format!("file is truncated while reading the {}", what));
+        }
+        let slice = &self.bytes[self.at..self.at + len];
+        self.at += len;
+        Ok(slice)
+    }
+
+    fn i32(&mut self, what: &str) -> Result<i32, String> {
+        let b = self.take(4, what)?;
+        Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.i32("string length")?.max(0) as usize;
+        let bytes = self.take(len, "string")?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn dict(&mut self) -> Result<Vec<(String, String)>, String> {
+        let count = self.i32("dictionary size")?.max(0);
+        (0..count).map(|_| Ok((self.string()?, self.string()?))).collect()
+    }
+
+    fn chunk(&mut self) -> Result<RawChunk<'a>, String> {
+        let id = self.take(4, "chunk id")?;
+        let content_len = self.i32("chunk size")?.max(0) as usize;
+        let children_len = self.i32("chunk children size")?.max(0) as usize;
+        let content = self.take(content_len, "chunk content")?;
+        let children = self.take(children_len, "chunk children")?;
+        Ok((id, content, children))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::RegionStore;
+    use crate::voxels::WorldParams;
+
+    // room for little more than the chunks around the camera
+    fn small_world(seed: u64) -> Box<World> {
+        let mut world = World::new(WorldParams::new(8, 2, 64 * 1024).unwrap(), seed);
+        world.load_area();
+        world
+    }
+
+    #[test]
+    fn exporting_stays_in_the_memory_budget() {
+        let (min, max) = (Pos::new(-40, -20, -40), Pos::new(39, 19, 39));
+        let mut large = World::new(WorldParams::default(), 3);
+        let expected = VoxScene::from_region(&mut large, min, max).unwrap();
+
+        let mut world = small_world(3);
+        let scene = VoxScene::from_region(&mut world, min, max).unwrap();
+        assert!(world.memory_usage() <= world.params().memory_budget);
+        assert_eq!(scene.voxels(), expected.voxels());
+    }
+
+    #[test]
+    fn importing_stays_in_the_memory_budget() {
+        let dir = std::env::temp_dir().join(format!("raytracer-vox-import-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (min, max) = (Pos::new(0, -20, 0), Pos::new(79, 19, 79));
+        let scene = VoxScene::from_region(&mut World::new(WorldParams::default(), 3), min, max).unwrap();
+
+        // seed 4 has different terrain to write over
+        let mut world = small_world(4);
+        world.attach_store(RegionStore::open(&dir, 8, 4).unwrap());
+        let written = scene.stamp(&mut world, Pos::new(100, 0, 100));
+        assert_eq!(written, scene.voxels().len());
+        assert!(world.memory_usage() <= world.params().memory_budget);
+
+        // the evicted chunks come back from the store
+        let min = scene.voxels().iter().map(|(p, _)| *p).reduce(|a, b| Pos::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z))).unwrap();
+        let map = scene.material_map();
+        for (p, color) in scene.voxels() {
+            let target = p.sub(&min).add(&Pos::new(100, 0, 100));
+            world.ensure_loaded(world.split_pos(target).0);
+            assert_eq!(world.voxel_at(target), Some(map[color as usize]));
+        }
+    }
+
+    fn vox_file(children: &[u8]) -> Vec<u8> {
+        let mut out = VOX_MAGIC.to_vec();
+        write_i32s(&mut out, &[VOX_VERSION]);
+        out.extend_from_slice(b"MAIN");
+        write_i32s(&mut out, &[0, children.len() as i32]);
+        out.extend_from_slice(children);
+        out
+    }
+
+    fn transform_node(out: &mut Vec<u8>, node: i32, child: i32, frame: &[(&str, &str)]) {
+        let mut content = Vec::new();
+        write_i32s(&mut content, &[node]);
+        write_dict(&mut content, &[]);
+        write_i32s(&mut content, &[child, -1, 0, 1]);
+        write_dict(&mut content, frame);
+        write_chunk(out, b"nTRN", &content);
+    }
+
+    fn shape_node(out: &mut Vec<u8>, node: i32, model: i32) {
+        let mut content = Vec::new();
+        write_i32s(&mut content, &[node]);
+        write_dict(&mut content, &[]);
+        write_i32s(&mut content, &[1, model]);
+        write_dict(&mut content, &[]);
+        write_chunk(out, b"nSHP", &content);
+    }
+
+    #[test]
+    fn rotation_bytes_give_the_column_and_sign_of_each_row() {
+        assert_eq!(VoxTransform::from_rotation_byte(0b0000_0100), Some(VoxTransform::identity()));
+        // first row in column 1, second in column 0, first row negative
+        let rotation = VoxTransform::from_rotation_byte(0b0001_0001).unwrap().rotation;
+        assert_eq!(rotation, [[0, -1, 0], [1, 0, 0], [0, 0, 1]]);
+        // every row negative
+        let rotation = VoxTransform::from_rotation_byte(0b0111_0100).unwrap().rotation;
+        assert_eq!(rotation, [[-1, 0, 0], [0, -1, 0], [0, 0, -1]]);
+        // two rows in the same column, or a column past the third
+        assert_eq!(VoxTransform::from_rotation_byte(0b0000_0000), None);
+        assert_eq!(VoxTransform::from_rotation_byte(0b0000_0111), None);
+    }
+
+    #[test]
+    fn scene_graph_places_models() {
+        let mut children = Vec::new();
+        write_chunk(&mut children, b"SIZE", &[2i32, 2, 2].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>());
+        let mut xyzi = Vec::new();
+        write_i32s(&mut xyzi, &[2]);
+        xyzi.extend_from_slice(&[0, 0, 0, 1, 1, 0, 0, 2]);
+        write_chunk(&mut children, b"XYZI", &xyzi);
+
+        // root transform -> group -> two transforms, each with a shape of the model
+        transform_node(&mut children, 0, 1, &[]);
+        let mut group = Vec::new();
+        write_i32s(&mut group, &[1]);
+        write_dict(&mut group, &[]);
+        write_i32s(&mut group, &[2, 2, 4]);
+        write_chunk(&mut children, b"nGRP", &group);
+        transform_node(&mut children, 2, 3, &[("_t", "10 0 0")]);
+        shape_node(&mut children, 3, 0);
+        transform_node(&mut children, 4, 5, &[("_r", "17"), ("_t", "0 5 0")]);
+        shape_node(&mut children, 5, 0);
+
+        let mut rgba = vec![0; 256 * 4];
+        rgba[..8].copy_from_slice(&[10, 20, 30, 255, 40, 50, 60, 255]);
+        write_chunk(&mut children, b"RGBA", &rgba);
+
+        let scene = VoxScene::parse(&vox_file(&children)).unwrap();
+        // the color stored first is palette index 1
+        assert_eq!(scene.palette[1], [10, 20, 30, 255]);
+        assert_eq!(scene.palette[2], [40, 50, 60, 255]);
+        assert_eq!(scene.models.len(), 1);
+        assert_eq!(scene.models[0].voxels, vec![(0, 0, 0, 1), (1, 0, 0, 2)]);
+
+        let rotated = VoxTransform {rotation: [[0, -1, 0], [1, 0, 0], [0, 0, 1]], translation: Pos::new(0, 5, 0)};
+        let moved = VoxTransform {translation: Pos::new(10, 0, 0), ..VoxTransform::identity()};
+        assert_eq!(scene.instances, vec![(0, moved), (0, rotated)]);
+
+        // relative to the model center (1, 1, 1), then z up to y up
+        let voxels: Vec<Pos> = scene.voxels().into_iter().filter(|(_, color)| *color == 2).map(|(p, _)| p).collect();
+        assert_eq!(voxels, vec![Pos::new(10, -1, 1), Pos::new(1, -1, -5)]);
+    }
+
+    #[test]
+    fn saved_scenes_parse_back() {
+        let model = VoxModel {size: Pos::new(3, 2, 4), voxels: vec![(0, 0, 0, 1), (2, 1, 3, 7), (1, 1, 1, 255)]};
+        let mut palette = default_palette();
+        palette[7] = [1, 2, 3, 4];
+        let instances = vec![
+            (0, VoxTransform::identity()),
+            (0, VoxTransform {translation: Pos::new(-3, 4, 100), ..VoxTransform::identity()}),
+        ];
+        let scene = VoxScene {models: vec![model], palette, instances, centered: true};
+
+        let parsed = VoxScene::parse(&scene.to_bytes()).unwrap();
+        assert_eq!(parsed.models.len(), 1);
+        assert_eq!(parsed.models[0].size, scene.models[0].size);
+        assert_eq!(parsed.models[0].voxels, scene.models[0].voxels);
+        assert_eq!(parsed.palette, scene.palette);
+        assert_eq!(parsed.instances, scene.instances);
+        assert_eq!(parsed.voxels(), scene.voxels());
+    }
+}
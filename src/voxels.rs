@@ -1,8 +1,10 @@
 
-use std::mem;
-use rand::Rng;
-
+use std::collections::{HashMap, HashSet};
 use crate::linalg::*;
+use crate::storage::RegionStore;
+use crate::savefile::{SaveReader, SaveWriter};
+use crate::generation::TerrainGenerator;
+use crate::compression::{VoxelStorage, StorageKind};
 
 
 
@@ -52,6 +54,14 @@ impl Color {
             a: c,
         }
     }
+
+    pub fn add(&self, other: &Color) -> Color {
+        Color {r: self.r + other.r, g: self.g + other.g, b: self.b + other.b, a: self.a + other.a}
+    }
+
+    pub fn scale(&self, scalar: f32) -> Color {
+        Color {r: self.r * scalar, g: self.g * scalar, b: self.b * scalar, a: self.a * scalar}
+    }
 }
 
 
@@ -65,6 +75,10 @@ static MATERIALS: [Material; 256] = Materials::init_pallete();
 #[derive(Clone, Copy, Debug)]
 pub struct Material {
     color: Color,
+    // falls when nothing holds it up and slides down the sides of piles
+    granular: bool,
+    // blocks the player
+    solid: bool,
     //reflectiveness: f32,
     //refractiviness: f32,
     //metallicness: f32,
@@ -74,6 +88,18 @@ impl Material {
     pub fn from_id(id: MaterialID) -> Material {
         MATERIALS[id as usize]
     }
+
+    pub fn color(&self) -> Color {
+        self.color
+    }
+
+    pub fn is_granular(&self) -> bool {
+        self.granular
+    }
+
+    pub fn is_solid(&self) -> bool {
+        self.solid
+    }
 }
 
 
@@ -86,6 +112,10 @@ pub enum Materials {
     Water,
     Sand,
     Wood,
+    Coal,
+    Iron,
+    Gold,
+    Leaves,
 }
 
 impl Materials {
@@ -94,15 +124,19 @@ impl Materials {
     }
 
     pub const fn init_pallete() -> [Material; 256] {
-        let air: Material = Material {color: Color {r: 0.0, g: 0.0, b: 0.0, a: 0.0}};
+        let air: Material = Material {color: Color {r: 0.0, g: 0.0, b: 0.0, a: 0.0}, granular: false, solid: false};
         let mut materials = [air; 256];
-        materials[Materials::Air as usize] = Material {color: Color {r: 0.0, g: 0.0, b: 0.0, a: 0.0}};
-        materials[Materials::Stone as usize] = Material {color: Color {r: 0.5725, g: 0.5569, b: 0.5216, a: 1.0}};
-        materials[Materials::Dirt as usize] = Material {color: Color {r: 0.5451, g: 0.2706, b: 0.0745, a: 1.0}};
-        materials[Materials::Grass as usize] = Material {color: Color {r: 0.0, g: 1.0, b: 0.0, a: 1.0}};
-        materials[Materials::Water as usize] = Material {color: Color {r: 0.0, g: 0.0, b: 1.0, a: 1.0}};
-        materials[Materials::Sand as usize] = Material {color: Color {r: 1.0, g: 1.0, b: 0.0, a: 1.0}};
-        materials[Materials::Wood as usize] = Material {color: Color {r: 0.8471, g: 0.7098, b: 0.5373, a: 1.0}};
+        materials[Materials::Air as usize] = Material {color: Color {r: 0.0, g: 0.0, b: 0.0, a: 0.0}, granular: false, solid: false};
+        materials[Materials::Stone as usize] = Material {color: Color {r: 0.5725, g: 0.5569, b: 0.5216, a: 1.0}, granular: false, solid: true};
+        materials[Materials::Dirt as usize] = Material {color: Color {r: 0.5451, g: 0.2706, b: 0.0745, a: 1.0}, granular: false, solid: true};
+        materials[Materials::Grass as usize] = Material {color: Color {r: 0.0, g: 1.0, b: 0.0, a: 1.0}, granular: false, solid: true};
+        materials[Materials::Water as usize] = Material {color: Color {r: 0.0, g: 0.0, b: 1.0, a: 1.0}, granular: false, solid: false};
+        materials[Materials::Sand as usize] = Material {color: Color {r: 1.0, g: 1.0, b: 0.0, a: 1.0}, granular: true, solid: true};
+        materials[Materials::Wood as usize] = Material {color: Color {r: 0.8471, g: 0.7098, b: 0.5373, a: 1.0}, granular: false, solid: true};
+        materials[Materials::Coal as usize] = Material {color: Color {r: 0.1765, g: 0.1765, b: 0.1882, a: 1.0}, granular: false, solid: true};
+        materials[Materials::Iron as usize] = Material {color: Color {r: 0.7686, g: 0.5647, b: 0.4510, a: 1.0}, granular: false, solid: true};
+        materials[Materials::Gold as usize] = Material {color: Color {r: 1.0, g: 0.8431, b: 0.0, a: 1.0}, granular: false, solid: true};
+        materials[Materials::Leaves as usize] = Material {color: Color {r: 0.1333, g: 0.5451, b: 0.1333, a: 1.0}, granular: false, solid: true};
         
         materials
     }
@@ -120,238 +154,627 @@ pub struct Octree {
 */
 
 
-const CHUNK_SIZE: usize = 8;
-const RENDER_DISTANCE: usize = 2;
+// Occupancy bricks: every chunk is split in 4x4x4 bricks, each one summarised by a 64 bit mask
+const BRICK_SIZE: usize = 4;
 
-const CHUNK_MASK: usize = RENDER_DISTANCE - 1;
-const VOXEL_MASK: usize = CHUNK_SIZE - 1;
+const MAX_CHUNK_SIZE: usize = 64;
+const MAX_RENDER_DISTANCE: usize = 32;
+const DEFAULT_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
 
-#[derive(Clone, Copy, Debug)]
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WorldParams {
+    pub chunk_size: usize,
+    pub render_distance: usize,
+    // bytes of chunk data kept in memory before the least recently used chunks get unloaded
+    pub memory_budget: usize,
+}
+
+impl WorldParams {
+    pub fn new(chunk_size: usize, render_distance: usize, memory_budget: usize) -> Result<WorldParams, String> {
+        if chunk_size == 0 || !chunk_size.is_multiple_of(BRICK_SIZE) || chunk_size > MAX_CHUNK_SIZE {
+            return Err(format!("chunk size must be a multiple of {} between {} and {}, got {}", 
+                BRICK_SIZE, BRICK_SIZE, MAX_CHUNK_SIZE, chunk_size));
+        }
+        if render_distance == 0 || render_distance > MAX_RENDER_DISTANCE {
+            return Err(format!("render distance must be between 1 and {}, got {}", 
+                MAX_RENDER_DISTANCE, render_distance));
+        }
+        let params = WorldParams {chunk_size, render_distance, memory_budget};
+        let area_size = render_distance.pow(3) * Chunk::dense_memory_usage(chunk_size);
+        if memory_budget < area_size {
+            return Err(format!("memory budget of {} bytes can't hold the {} bytes of chunks in render distance", 
+                memory_budget, area_size));
+        }
+        Ok(params)
+    }
+
+    pub fn chunk_volume(&self) -> usize {
+        self.chunk_size * self.chunk_size * self.chunk_size
+    }
+
+    pub fn view_distance(&self) -> f32 {
+        (self.chunk_size * self.render_distance) as f32
+    }
+}
+
+impl Default for WorldParams {
+    fn default() -> Self {
+        WorldParams {chunk_size: 8, render_distance: 2, memory_budget: DEFAULT_MEMORY_BUDGET}
+    }
+}
+
+
+#[derive(Clone, Debug)]
 pub struct Chunk {
     //tree: Octree,
     coords: Pos,
-    voxels: [MaterialID; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE],
+    size: usize,
+    voxels: VoxelStorage,
+    bricks: Vec<u64>,
+    // bricks with at least one voxel set, so telling whether the chunk is empty doesn't scan the masks
+    occupied_bricks: usize,
+    // sparse per voxel state on top of the material, like the fill level of water. Voxels without
+    // an entry read as 0, and changing a voxel's material drops its entry
+    metadata: HashMap<usize, u8>,
+    // edited since it was generated or last saved
+    modified: bool,
 }
 
 impl Chunk {
-    pub fn new(coords: Pos) -> Box<Chunk> {
-        Box::new(Chunk {coords, voxels: [0; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE]})
+    pub fn new(coords: Pos, size: usize) -> Box<Chunk> {
+        let bricks_per_axis = size / BRICK_SIZE;
+        Box::new(Chunk {
+            coords, 
+            size,
+            voxels: VoxelStorage::new(Materials::Air as MaterialID),
+            bricks: vec![0; bricks_per_axis * bricks_per_axis * bricks_per_axis],
+            occupied_bricks: 0,
+            metadata: HashMap::new(),
+            modified: false,
+        })
+    }
+
+    pub fn from_bytes(coords: Pos, size: usize, bytes: &[u8]) -> Result<Box<Chunk>, String> {
+        if bytes.len() != size * size * size {
+            return Err(format!("chunk {:?} has {} voxels, expected {}", coords, bytes.len(), size * size * size));
+        }
+        let mut chunk = Chunk::new(coords, size);
+        for (index, &material) in bytes.iter().enumerate() {
+            let x = index % size;
+            let y = index / size % size;
+            let z = index / (size * size);
+            chunk.set_voxel(Vec3::new(x as f32, y as f32, z as f32), material);
+        }
+        chunk.compact();
+        Ok(chunk)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.voxels.to_vec(self.volume())
     }
 
-    pub fn random_gen(&mut self) {
-        let mut rng = rand::thread_rng();
-        for _ in 0..CHUNK_SIZE {
-            let x = rng.gen_range(0..CHUNK_SIZE);
-            let y = rng.gen_range(0..CHUNK_SIZE);
-            let z = rng.gen_range(0..CHUNK_SIZE);
-            let index = ((z * CHUNK_SIZE) + y) * CHUNK_SIZE + x;
-            self.voxels[index] = Materials::Stone as MaterialID;
+    // the metadata of every voxel, in the same order as to_bytes
+    pub fn metadata_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; self.volume()];
+        for (&index, &value) in &self.metadata {
+            bytes[index] = value;
         }
+        bytes
+    }
+
+    pub fn set_metadata_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if bytes.len() != self.volume() {
+            return Err(format!("chunk {:?} has metadata for {} voxels, expected {}", self.coords, bytes.len(), self.volume()));
+        }
+        self.metadata = bytes.iter().enumerate().filter(|(_, &value)| value != 0).map(|(index, &value)| (index, value)).collect();
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn coords(&self) -> Pos {
+        self.coords
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    pub fn volume(&self) -> usize {
+        self.size * self.size * self.size
+    }
+
+    pub fn storage_kind(&self) -> StorageKind {
+        self.voxels.kind()
+    }
+
+    // switches to the smallest representation of the voxels, worth doing once a chunk is done being generated or edited
+    pub fn compact(&mut self) {
+        self.voxels.compact(self.volume());
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Chunk>() + self.voxels.memory_usage() + self.bricks.len() * std::mem::size_of::<u64>()
+            + self.metadata.capacity() * (std::mem::size_of::<usize>() + std::mem::size_of::<u8>())
+    }
+
+    fn dense_memory_usage(size: usize) -> usize {
+        let bricks_per_axis = size / BRICK_SIZE;
+        std::mem::size_of::<Chunk>() + size.pow(3) * std::mem::size_of::<MaterialID>() + bricks_per_axis.pow(3) * std::mem::size_of::<u64>()
+    }
+
+    fn local_coords(&self, coords: Vec3) -> (usize, usize, usize) {
+        let local = coords.pos().modulo(self.size as i32);
+        (local.x as usize, local.y as usize, local.z as usize)
+    }
+
+    fn get_voxel_index(&self, coords: Vec3) -> usize {
+        let (x, y, z) = self.local_coords(coords);
+        ((z * self.size) + y) * self.size + x
     }
 
-    fn get_voxel_index(coords: Vec3) -> usize {
-        let x: usize = coords.x as usize & VOXEL_MASK;
-        let y: usize = coords.y as usize & VOXEL_MASK;
-        let z: usize = coords.z as usize & VOXEL_MASK;
-        let index = ((z * CHUNK_SIZE) + y) * CHUNK_SIZE + x;
-        index
+    // returns the index of the brick and the bit of the voxel inside that brick's mask
+    fn get_brick_bit(&self, coords: Vec3) -> (usize, u32) {
+        let (x, y, z) = self.local_coords(coords);
+        let bricks_per_axis = self.size / BRICK_SIZE;
+        let brick = ((z / BRICK_SIZE * bricks_per_axis) + y / BRICK_SIZE) * bricks_per_axis + x / BRICK_SIZE;
+        let bit = ((z % BRICK_SIZE * BRICK_SIZE) + y % BRICK_SIZE) * BRICK_SIZE + x % BRICK_SIZE;
+        (brick, bit as u32)
     }
 
     pub fn get_voxel(&self, coords: Vec3) -> MaterialID {
-        let index = Chunk::get_voxel_index(coords);
-        self.voxels[index]
+        let index = self.get_voxel_index(coords);
+        self.voxels.get(index)
     }
 
     pub fn set_voxel(&mut self, coords: Vec3, material: MaterialID) {
-        let index = Chunk::get_voxel_index(coords);
-        self.voxels[index] = material;
+        let index = self.get_voxel_index(coords);
+        let volume = self.volume();
+        if !self.metadata.is_empty() && self.voxels.get(index) != material {
+            self.metadata.remove(&index);
+        }
+        self.voxels.set(index, material, volume);
+
+        let (brick, bit) = self.get_brick_bit(coords);
+        let was_empty = self.bricks[brick] == 0;
+        if material == Materials::Air as MaterialID {
+            self.bricks[brick] &= !(1 << bit);
+        }
+        else {
+            self.bricks[brick] |= 1 << bit;
+        }
+        match (was_empty, self.bricks[brick] == 0) {
+            (true, false) => self.occupied_bricks += 1,
+            (false, true) => self.occupied_bricks -= 1,
+            _ => {},
+        }
     }
 
+    pub fn get_metadata(&self, coords: Vec3) -> u8 {
+        self.metadata.get(&self.get_voxel_index(coords)).copied().unwrap_or(0)
+    }
+
+    pub fn set_metadata(&mut self, coords: Vec3, value: u8) {
+        let index = self.get_voxel_index(coords);
+        if value == 0 {
+            self.metadata.remove(&index);
+        }
+        else {
+            self.metadata.insert(index, value);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.occupied_bricks == 0
+    }
+
+    pub fn is_brick_empty(&self, coords: Vec3) -> bool {
+        let (brick, _) = self.get_brick_bit(coords);
+        self.bricks[brick] == 0
+    }
+
+}
+
+
+// What the ray traversal sees when it looks at a voxel position
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Occupancy {
+    Unloaded,
+    EmptyChunk,
+    EmptyBrick,
+    Voxel(MaterialID),
+}
+
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryStats {
+    pub chunks: usize,
+    pub uniform: usize,
+    pub palette: usize,
+    pub dense: usize,
+    pub bytes: usize,
+    // what the same chunks would take stored one byte per voxel
+    pub dense_bytes: usize,
+}
+
+impl std::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let saved = 100.0 * (1.0 - self.bytes as f32 / self.dense_bytes.max(1) as f32);
+        write!(f, "{} chunks ({} uniform, {} palette, {} dense) using {:.1} KiB instead of {:.1} KiB, {:.1}% saved",
+            self.chunks, self.uniform, self.palette, self.dense, 
+            self.bytes as f32 / 1024.0, self.dense_bytes as f32 / 1024.0, saved)
+    }
+}
+
+
+// Sent to the subscribers of a world whenever a chunk enters or leaves memory
+#[derive(Clone, Copy, Debug)]
+pub enum ChunkEvent<'a> {
+    Loaded(&'a Chunk),
+    Unloaded(&'a Chunk),
 }
 
+pub type ChunkListener = Box<dyn FnMut(&ChunkEvent)>;
+
+
+struct LoadedChunk {
+    chunk: Box<Chunk>,
+    last_used: u64,
+}
 
-#[derive(Debug)]
 pub struct World {
-    chunks: [Option<Box<Chunk>>; RENDER_DISTANCE * RENDER_DISTANCE * RENDER_DISTANCE],
+    params: WorldParams,
+    chunks: HashMap<Pos, LoadedChunk>,
     coord_index: Pos,
+    clock: u64,
+    listeners: Vec<ChunkListener>,
+    store: Option<RegionStore>,
+    generator: TerrainGenerator,
+    // chunks whose voxels changed since the simulation last looked
+    changed_chunks: HashSet<Pos>,
 }
 
 impl World {
-    pub fn new() -> Box<World> {
+    // the same seed always generates the same terrain
+    pub fn new(params: WorldParams, seed: u64) -> Box<World> {
         Box::new(World {
-            chunks: std::array::from_fn(|_| None),
+            params,
+            chunks: HashMap::new(),
             coord_index: Pos::new(0, 0, 0),
+            clock: 0,
+            listeners: Vec::new(),
+            store: None,
+            generator: TerrainGenerator::new(seed),
+            changed_chunks: HashSet::new(),
         })
     }
 
-    pub fn random_gen(&mut self) {
-        for x in 0..RENDER_DISTANCE {
-            for y in 0..RENDER_DISTANCE {
-                for z in 0..RENDER_DISTANCE {
-                    let mut chunk = Chunk::new(Pos::new(x as i32, y as i32, z as i32));
-                    chunk.random_gen();
-                    self.load_chunk(chunk);
-                }
-            }
-        }
+    // modified chunks are written to the store when unloaded, and read back instead of being regenerated
+    pub fn attach_store(&mut self, store: RegionStore) {
+        self.store = Some(store);
     }
 
-    fn chunk_index(coords: Pos) -> usize {
-        let x: usize = coords.x as usize & CHUNK_MASK;
-        let y: usize = coords.y as usize & CHUNK_MASK;
-        let z: usize = coords.z as usize & CHUNK_MASK;
-        let index = (z * RENDER_DISTANCE + y) * RENDER_DISTANCE + x;
-        index
+    pub fn params(&self) -> WorldParams {
+        self.params
     }
 
-    fn fetch_unloaded_chunk(&self, coords: Pos) -> Box<Chunk> {
-        let was_generated = false;
+    pub fn generator(&self) -> &TerrainGenerator {
+        &self.generator
+    }
 
-        if was_generated {
-            // TODO load from file
-            let c = Chunk::new(coords);
-            c
-        }
-        else {
-            let mut c = Chunk::new(coords);
-            c.random_gen();
-            c
+    pub fn seed(&self) -> u64 {
+        self.generator.seed()
+    }
+
+    pub fn subscribe(&mut self, listener: impl FnMut(&ChunkEvent) + 'static) {
+        self.listeners.push(Box::new(listener));
+    }
+
+    fn notify(listeners: &mut [ChunkListener], event: ChunkEvent) {
+        for listener in listeners.iter_mut() {
+            listener(&event);
         }
     }
 
-    pub fn load_chunk(&mut self, chunk: Box<Chunk>) -> Option<Box<Chunk>> {
-        let index = World::chunk_index(chunk.coords);
+    pub fn load_area(&mut self) {
+        for coords in self.area_around(self.coord_index) {
+            self.ensure_loaded(coords);
+        }
+    }
 
-        let old_chunk = mem::replace(&mut self.chunks[index], Some(chunk));
+    // coordinates of every chunk that is kept loaded while the camera is inside the chunk center
+    fn area_around(&self, center: Pos) -> Vec<Pos> {
+        let rd = self.params.render_distance as i32;
+        let corner = center.sub(&Pos::new(rd/2, rd/2, rd/2));
+        let mut area = Vec::with_capacity((rd * rd * rd) as usize);
+        for x in 0..rd {
+            for y in 0..rd {
+                for z in 0..rd {
+                    area.push(corner.add(&Pos::new(x, y, z)));
+                }
+            }
+        }
+        area
+    }
 
-        old_chunk
+    pub fn get_chunk(&self, coords: Pos) -> Option<&Chunk> {
+        self.chunks.get(&coords).map(|loaded| loaded.chunk.as_ref())
     }
 
-    pub fn unload_chunk(&mut self, coords: Pos) -> Option<Box<Chunk>> {
-        let index = World::chunk_index(coords);
+    pub fn get_chunk_mut(&mut self, coords: Pos) -> Option<&mut Chunk> {
+        self.chunks.get_mut(&coords).map(|loaded| loaded.chunk.as_mut())
+    }
 
-        let old = mem::replace(&mut self.chunks[index], None);
+    pub fn loaded_chunks(&self) -> impl Iterator<Item = &Chunk> {
+        self.chunks.values().map(|loaded| loaded.chunk.as_ref())
+    }
 
-        old
+    pub fn memory_usage(&self) -> usize {
+        self.loaded_chunks().map(|chunk| chunk.memory_usage()).sum()
     }
 
-    pub fn voxel_at(&self, pos: Pos) -> Option<MaterialID> {
-        let chunk_coords = Pos::new(pos.x / CHUNK_SIZE as i32, pos.y / CHUNK_SIZE as i32, pos.z / CHUNK_SIZE as i32);
-        let voxel_offset = Pos::new(pos.x % CHUNK_SIZE as i32, pos.y % CHUNK_SIZE as i32, pos.z % CHUNK_SIZE as i32);
-        let chunk = &self.chunks[World::chunk_index(chunk_coords)];
-        if let Some(chunk) = chunk {
-            Some(chunk.get_voxel(voxel_offset.vec3()))
-        }
-        else {
-            None
+    pub fn memory_stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        for chunk in self.loaded_chunks() {
+            stats.chunks += 1;
+            match chunk.storage_kind() {
+                StorageKind::Uniform => stats.uniform += 1,
+                StorageKind::Palette => stats.palette += 1,
+                StorageKind::Dense => stats.dense += 1,
+            }
+            stats.bytes += chunk.memory_usage();
+            stats.dense_bytes += Chunk::dense_memory_usage(chunk.size);
         }
+        stats
     }
 
-    pub fn update_chunks_in_area(&mut self, pos: Vec3) {
-        const RD: i32 = RENDER_DISTANCE as i32;
-        
-        let pos = pos.pos();
-        let index = pos.div(CHUNK_SIZE as i32);
-        if index == self.coord_index { 
-            return; // early return as no changes to loaded chunks
-        }
-        let offset = index.sub(&self.coord_index);
+    // saves every modified chunk that is still loaded
+    pub fn flush(&mut self) -> Result<(), String> {
+        let Some(store) = &self.store else { return Ok(()); };
+        let modified: Vec<&Chunk> = self.loaded_chunks().filter(|chunk| chunk.modified).collect();
+        store.save_chunks(&modified)?;
 
-        let mut unloaded_chunks: Vec<Box<Chunk>> = Vec::new();
+        for loaded in self.chunks.values_mut() {
+            loaded.chunk.modified = false;
+            loaded.chunk.compact();
+        }
+        Ok(())
+    }
 
-        if offset.x >= RD || offset.y >= RD || offset.z >= RD {
-            unloaded_chunks.append(&mut self.unload_all_chunks());
-            self.load_all_chunks(pos);
+    // writes every chunk that differs from generation, loaded or in the region store, to a single save file.
+    // Stored chunks are read one region at a time, so a large store doesn't have to fit in memory
+    pub fn save(&self, path: &std::path::Path) -> Result<(), String> {
+        let mut writer = SaveWriter::create(path, self.params, self.seed(), self.coord_index)?;
+        // loaded chunks that aren't modified are either generated or the same as their stored copy
+        for chunk in self.loaded_chunks().filter(|chunk| chunk.modified) {
+            writer.write_chunk(chunk)?;
         }
-        
-        let corner = self.coord_index.sub(&Pos::new(RD/2, RD/2, RD/2));
-        
-        let range_x = if offset.x >= 0 { 0..offset.x } 
-                                else { (RD + offset.x)..RD };
-        for x in range_x {
-            for y in 0..RENDER_DISTANCE {
-                for z in 0..RENDER_DISTANCE {
-                    let coords = Pos::new(x, y as i32, z as i32).sub(&corner);
-                    let new = self.fetch_unloaded_chunk(coords);
-                    let old = self.load_chunk(new);
-                    if let Some(old) = old {
-                        unloaded_chunks.push(old);
+        if let Some(store) = &self.store {
+            for region in store.regions()? {
+                for chunk in store.region_chunks(region)? {
+                    if !self.get_chunk(chunk.coords).is_some_and(|loaded| loaded.modified) {
+                        writer.write_chunk(&chunk)?;
                     }
                 }
             }
         }
+        writer.finish()
+    }
 
-        let range_y = if offset.y >= 0 { 0..offset.y } 
-                                else { (RD + offset.y)..RD };
-        for y in range_y {
-            let range_x = if offset.x >= 0 { offset.x..RD } // feels like backwards but its correct
-                                    else { 0..(RD + offset.x) };
-            
-            for x in range_x {
-                for z in 0..RENDER_DISTANCE {
-                    let coords = Pos::new(x, y, z as i32).sub(&corner);
-                    let c = self.unload_chunk(coords);
-                    if let Some(c) = c {
-                        unloaded_chunks.push(c);
-                    }
-                }
-            }
+    // Chunks of the save count as modified. With a store, the ones outside the area around the center go
+    // straight to it in batches instead of being loaded, so a large save doesn't have to fit in memory
+    pub fn load(path: &std::path::Path, save_dir: Option<&std::path::Path>) -> Result<Box<World>, String> {
+        let mut reader = SaveReader::open(path)?;
+        let mut world = World::new(reader.params, reader.seed);
+        world.coord_index = reader.center;
+        if let Some(dir) = save_dir {
+            world.attach_store(RegionStore::open(dir, world.params.chunk_size, world.seed())?);
         }
 
-        let range_z = if offset.z >= 0 { 0..offset.z } 
-                                else { (RD + offset.z)..RD };
-        for z in range_z {
-            let range_x = if offset.x >= 0 { offset.x..RD }
-                                    else { 0..(RD + offset.x) };
-            
-            for x in range_x {
-                let range_y = if offset.y >= 0 { offset.y..RD }
-                                        else { 0..(RD + offset.y) };
-                for y in range_y {
-                    let coords = Pos::new(x, y, z).sub(&corner);
-                    let c = self.unload_chunk(coords);
-                    if let Some(c) = c {
-                        unloaded_chunks.push(c);
-                    }
-                }
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        while let Some(mut chunk) = reader.next_chunk()? {
+            chunk.modified = true;
+            let Some(store) = world.store.as_ref().filter(|_| !world.in_area(chunk.coords)) else {
+                world.load_chunk(chunk);
+                continue;
+            };
+
+            chunk.compact();
+            batch_bytes += chunk.memory_usage();
+            batch.push(chunk);
+            if batch_bytes > world.params.memory_budget / 2 {
+                store.save_chunks(&batch.iter().map(|chunk| chunk.as_ref()).collect::<Vec<_>>())?;
+                batch.clear();
+                batch_bytes = 0;
             }
         }
+        if let Some(store) = &world.store {
+            store.save_chunks(&batch.iter().map(|chunk| chunk.as_ref()).collect::<Vec<_>>())?;
+        }
+        Ok(world)
+    }
+
+    fn fetch_unloaded_chunk(&self, coords: Pos) -> Box<Chunk> {
+        let saved = match &self.store {
+            Some(store) => store.load_chunk(coords).unwrap_or_else(|e| {
+                eprintln!("regenerating chunk {:?}: {}", coords, e);
+                None
+            }),
+            None => None,
+        };
+
+        match saved {
+            Some(c) => c,
+            None => self.generator.generate(coords, self.params.chunk_size),
+        }
+    }
 
-        for c in unloaded_chunks {
-            println!("{:?}\n", c.coords);
+    pub fn ensure_loaded(&mut self, coords: Pos) {
+        if !self.chunks.contains_key(&coords) {
+            let chunk = self.fetch_unloaded_chunk(coords);
+            self.load_chunk(chunk);
         }
+    }
+
+    pub fn load_chunk(&mut self, mut chunk: Box<Chunk>) -> Option<Box<Chunk>> {
+        let coords = chunk.coords;
+        chunk.compact();
+        let old = self.unload_chunk(coords);
         
-        self.coord_index = index;    
-    }
-
-    fn unload_all_chunks(&mut self) -> Vec<Box<Chunk>> {
-        let mut unloaded_chunks = Vec::new();
-        for x in 0..RENDER_DISTANCE {
-            for y in 0..RENDER_DISTANCE {
-                for z in 0..RENDER_DISTANCE {
-                    let coords = Pos::new(x as i32, y as i32, z as i32);
-                    let c = self.unload_chunk(coords);
-                    if let Some(c) = c {
-                        unloaded_chunks.push(c);
-                    }
-                }
+        self.chunks.insert(coords, LoadedChunk {chunk, last_used: self.clock});
+        World::notify(&mut self.listeners, ChunkEvent::Loaded(&self.chunks[&coords].chunk));
+        old
+    }
+
+    pub fn unload_chunk(&mut self, coords: Pos) -> Option<Box<Chunk>> {
+        let chunk = self.remove_chunk(coords)?;
+        self.store_chunks(&[&chunk]);
+        Some(chunk)
+    }
+
+    // takes a chunk out of memory without saving it
+    fn remove_chunk(&mut self, coords: Pos) -> Option<Box<Chunk>> {
+        let old = self.chunks.remove(&coords)?;
+        World::notify(&mut self.listeners, ChunkEvent::Unloaded(&old.chunk));
+        Some(old.chunk)
+    }
+
+    // writes the modified ones of chunks leaving memory to the store
+    fn store_chunks(&self, chunks: &[&Chunk]) {
+        let Some(store) = &self.store else { return; };
+        let modified: Vec<&Chunk> = chunks.iter().copied().filter(|chunk| chunk.modified).collect();
+        if let Err(e) = store.save_chunks(&modified) {
+            let coords: Vec<Pos> = modified.iter().map(|chunk| chunk.coords).collect();
+            eprintln!("lost edits of chunks {:?}: {}", coords, e);
+        }
+    }
+
+    // unloads the least recently used chunks until the loaded ones fit in the memory budget, saving
+    // them together so every region is written once. Chunks used in the current clock tick are never evicted
+    fn evict_over_budget(&mut self) {
+        let mut used = self.memory_usage();
+        let mut evicted = Vec::new();
+        while used > self.params.memory_budget {
+            // without a store, edits only live in memory
+            // ties go to the lowest coordinates, so the same chunks are evicted every run
+            let oldest = self.chunks.iter()
+                .filter(|(coords, loaded)| loaded.last_used < self.clock && !self.in_area(**coords))
+                .filter(|(_, loaded)| self.store.is_some() || !loaded.chunk.modified)
+                .min_by_key(|(coords, loaded)| (loaded.last_used, coords.x, coords.y, coords.z))
+                .map(|(coords, _)| *coords);
+
+            let Some(coords) = oldest else { break; };
+            if let Some(chunk) = self.remove_chunk(coords) {
+                used -= chunk.memory_usage();
+                evicted.push(chunk);
+            }
+        }
+        let evicted: Vec<&Chunk> = evicted.iter().map(|chunk| chunk.as_ref()).collect();
+        self.store_chunks(&evicted);
+    }
+
+    // For work over more chunks than fit in memory, like exporting a large region. The chunks loaded
+    // so far can be evicted from here on, except the ones around the camera
+    pub fn release_chunks(&mut self) {
+        self.clock += 1;
+        self.evict_over_budget();
+    }
+
+    fn in_area(&self, coords: Pos) -> bool {
+        let rd = self.params.render_distance as i32;
+        let offset = coords.sub(&self.coord_index.sub(&Pos::new(rd/2, rd/2, rd/2)));
+        (0..rd).contains(&offset.x) && (0..rd).contains(&offset.y) && (0..rd).contains(&offset.z)
+    }
+
+    // the chunk containing pos and the position of the voxel inside it
+    pub fn split_pos(&self, pos: Pos) -> (Pos, Pos) {
+        let cs = self.params.chunk_size as i32;
+        (pos.div(cs), pos.modulo(cs))
+    }
+
+    pub fn voxel_at(&self, pos: Pos) -> Option<MaterialID> {
+        let (chunk_coords, voxel_offset) = self.split_pos(pos);
+        self.get_chunk(chunk_coords).map(|chunk| chunk.get_voxel(voxel_offset.vec3()))
+    }
+
+    pub fn set_voxel(&mut self, pos: Pos, material: MaterialID) -> bool {
+        let (chunk_coords, voxel_offset) = self.split_pos(pos);
+        if let Some(loaded) = self.chunks.get_mut(&chunk_coords) {
+            if loaded.chunk.get_voxel(voxel_offset.vec3()) != material {
+                self.changed_chunks.insert(chunk_coords);
+            }
+            loaded.chunk.set_voxel(voxel_offset.vec3(), material);
+            loaded.chunk.modified = true;
+            loaded.last_used = self.clock;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    pub fn metadata_at(&self, pos: Pos) -> Option<u8> {
+        let (chunk_coords, voxel_offset) = self.split_pos(pos);
+        self.get_chunk(chunk_coords).map(|chunk| chunk.get_metadata(voxel_offset.vec3()))
+    }
+
+    pub fn set_metadata(&mut self, pos: Pos, value: u8) -> bool {
+        let (chunk_coords, voxel_offset) = self.split_pos(pos);
+        if let Some(loaded) = self.chunks.get_mut(&chunk_coords) {
+            if loaded.chunk.get_metadata(voxel_offset.vec3()) != value {
+                self.changed_chunks.insert(chunk_coords);
             }
+            loaded.chunk.set_metadata(voxel_offset.vec3(), value);
+            loaded.chunk.modified = true;
+            loaded.last_used = self.clock;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    // sorted, so systems looking at them run in the same order every time
+    pub fn take_changed_chunks(&mut self) -> Vec<Pos> {
+        let mut changed: Vec<Pos> = self.changed_chunks.drain().collect();
+        changed.sort_by_key(|c| (c.x, c.y, c.z));
+        changed
+    }
+
+    pub fn occupancy(&self, pos: Pos) -> Occupancy {
+        let (chunk_coords, voxel_offset) = self.split_pos(pos);
+        let voxel_offset = voxel_offset.vec3();
+        match self.get_chunk(chunk_coords) {
+            None => Occupancy::Unloaded,
+            Some(chunk) if chunk.is_empty() => Occupancy::EmptyChunk,
+            Some(chunk) if chunk.is_brick_empty(voxel_offset) => Occupancy::EmptyBrick,
+            Some(chunk) => Occupancy::Voxel(chunk.get_voxel(voxel_offset)),
         }
-        unloaded_chunks
     }
 
-    fn load_all_chunks(&mut self, pos: Pos) {
-        let index = pos.div(CHUNK_SIZE as i32);
+    pub fn update_chunks_in_area(&mut self, pos: Vec3) {
+        let index = pos.pos().div(self.params.chunk_size as i32);
+        if index == self.coord_index { 
+            return; // early return as no changes to loaded chunks
+        }
         self.coord_index = index;
-        for x in 0..RENDER_DISTANCE {
-            for y in 0..RENDER_DISTANCE {
-                for z in 0..RENDER_DISTANCE {
-                    let coords = Pos::new(x as i32, y as i32, z as i32);
-                    let chunk = Chunk::new(coords);
-                    self.load_chunk(chunk);
-                }
+        self.clock += 1;
+
+        for coords in self.area_around(index) {
+            match self.chunks.get_mut(&coords) {
+                Some(loaded) => loaded.last_used = self.clock,
+                None => {
+                    let new = self.fetch_unloaded_chunk(coords);
+                    self.load_chunk(new);
+                },
             }
         }
+
+        self.evict_over_budget();
     }
 
 }
@@ -369,98 +792,177 @@ impl Ray {
     }
 
     pub fn trace(&self, world: &World, bounces_left: u8) -> Color {
-        let mut pos = self.origin;
-        while pos.manhattan(&self.origin) < (RENDER_DISTANCE * CHUNK_SIZE) as f32 {
-            let voxel = world.voxel_at(pos.pos());
-            if let Some(voxel) = voxel {
-                let color = Material::from_id(voxel).color;
-                
-                if color.a != 0.0 {
-                    if color.a == 1.0 || bounces_left <= 0 {
-                        return color;
+        self.march(world, bounces_left, true)
+    }
+
+    // Walks the voxel grid cell by cell. With skip_empty, whole empty bricks and chunks are crossed in a single step
+    pub fn march(&self, world: &World, bounces_left: u8, skip_empty: bool) -> Color {
+        let params = world.params();
+        let max_dist = params.view_distance();
+        let mut dist: f32 = 0.0;
+        let mut vox = self.origin.pos();
+
+        while dist < max_dist {
+            let cell_size = match world.occupancy(vox) {
+                Occupancy::Unloaded => break,
+                Occupancy::EmptyChunk if skip_empty => params.chunk_size as i32,
+                Occupancy::EmptyBrick if skip_empty => BRICK_SIZE as i32,
+                Occupancy::Voxel(voxel) => {
+                    let color = Material::from_id(voxel).color;
+                    if color.a != 0.0 {
+                        if color.a == 1.0 || bounces_left == 0 {
+                            return color;
+                        }
+
+                        let (next_dist, _) = self.exit_cell(vox, 1, dist);
+                        let ray = Ray::new(self.origin.add(&self.direction.scale(next_dist)), self.direction);
+                        let behind = ray.march(world, bounces_left - 1, skip_empty);
+
+                        return color.weight_mix(behind);
                     }
-                    
-                    let ray = Ray::new(self.origin, self.direction);
-                    let color = ray.trace(world, bounces_left - 1);
-                    
-                    return color.weight_mix(color);
-                }
-            }
-            else { break; }
+                    1
+                },
+                _ => 1,
+            };
 
-            pos = pos.add(&self.direction);
+            (dist, vox) = self.exit_cell(vox, cell_size, dist);
         }
 
         Materials::Air.get_properties().color
     }
-    
-    /*
-    pub fn trace(&self, world: &World, bounces_left: u8) -> Color {
-        let step_size_frac = Vec3::new(
-            (1.0 / self.direction.x).abs(), 
-            (1.0 / self.direction.y).abs(), 
-            (1.0 / self.direction.z).abs()
-        );
-        
-        let step_sign = Pos::new(
-            self.direction.x.signum() as i32, 
-            self.direction.y.signum() as i32, 
-            self.direction.z.signum() as i32
-        );
 
-        let mut vox = self.origin.pos();
-        let mut vox_frac: Vec3 = Vec3::new(
-            self.direction.x.signum() * (self.origin.x - (vox.x + (self.direction.x < 0.0) as i32) as f32) * step_size_frac.x,
-            self.direction.x.signum() * (self.origin.y - (vox.y + (self.direction.y < 0.0) as i32) as f32) * step_size_frac.y,
-            self.direction.z.signum() * (self.origin.z - (vox.z + (self.direction.z < 0.0) as i32) as f32) * step_size_frac.z
-        );
-        
+    // the first solid voxel within max_dist, and the voxel the ray was in just before it
+    pub fn cast(&self, world: &World, max_dist: f32) -> Option<(Pos, Pos)> {
         let mut dist: f32 = 0.0;
+        let mut vox = self.origin.pos();
+        let mut before = vox;
+        while dist < max_dist {
+            if Material::from_id(world.voxel_at(vox)?).is_solid() {
+                return Some((vox, before));
+            }
+            before = vox;
+            (dist, vox) = self.exit_cell(vox, 1, dist);
+        }
+        None
+    }
 
-        while dist < (RENDER_DISTANCE * CHUNK_SIZE) as f32 {
-            let voxel = world.voxel_at(vox);
-            if let Some(voxel) = voxel {
-                let color = Material::from_id(voxel).color;
-                
-                if color.a != 0.0 {
-                    if color.a == 1.0 || bounces_left <= 0 {
-                        return color;
-                    }
-                    
-                    let ray = Ray::new(vox_frac, self.direction);
-                    let color = ray.trace(world, bounces_left - 1);
-                    
-                    return color.weight_mix(color);
-                }
+    // Finds where the ray leaves the axis aligned cell of side cell_size that contains vox, 
+    // returning the distance travelled and the first voxel past the boundary
+    fn exit_cell(&self, vox: Pos, cell_size: i32, dist: f32) -> (f32, Pos) {
+        let cell_min = vox.sub(&vox.modulo(cell_size));
+
+        let axis_exit = |origin: f32, dir: f32, min: i32| -> f32 {
+            if dir > 0.0 {
+                ((min + cell_size) as f32 - origin) / dir
+            }
+            else if dir < 0.0 {
+                (min as f32 - origin) / dir
             }
-            else { break; }
-
-            if vox_frac.x < vox_frac.y {
-                if vox_frac.x < vox_frac.z {
-                    vox.x += step_sign.x;
-                    dist = vox_frac.x;
-                    vox_frac.x += step_size_frac.x;
-                } 
-                else {
-                    vox.z += step_sign.z;
-                    dist = vox_frac.z;
-                    vox_frac.z += step_size_frac.z;
-                }
-            } 
             else {
-                if vox_frac.y < vox_frac.z {
-                    vox.y += step_sign.y;
-                    dist = vox_frac.y;
-                    vox_frac.y += step_size_frac.y;
-                } 
-                else {
-                    vox.z += step_sign.z;
-                    dist = vox_frac.z;
-                    vox_frac.z += step_size_frac.z;
+                f32::INFINITY
+            }
+        };
+        let exit_x = axis_exit(self.origin.x, self.direction.x, cell_min.x);
+        let exit_y = axis_exit(self.origin.y, self.direction.y, cell_min.y);
+        let exit_z = axis_exit(self.origin.z, self.direction.z, cell_min.z);
+        let exit = exit_x.min(exit_y).min(exit_z).max(dist);
+
+        // the axes we did not cross stay inside the cell, the crossed one steps just past its face
+        let p = self.origin.add(&self.direction.scale(exit));
+        let inside = |v: f32, min: i32| (v.floor() as i32).clamp(min, min + cell_size - 1);
+        let past = |dir: f32, min: i32| if dir > 0.0 { min + cell_size } else { min - 1 };
+        let next = if exit_x <= exit_y && exit_x <= exit_z {
+            Pos::new(past(self.direction.x, cell_min.x), inside(p.y, cell_min.y), inside(p.z, cell_min.z))
+        }
+        else if exit_y <= exit_z {
+            Pos::new(inside(p.x, cell_min.x), past(self.direction.y, cell_min.y), inside(p.z, cell_min.z))
+        }
+        else {
+            Pos::new(inside(p.x, cell_min.x), inside(p.y, cell_min.y), past(self.direction.z, cell_min.z))
+        };
+
+        (exit, next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the 2x2x2 chunks around the origin, empty
+    fn world_around_origin() -> Box<World> {
+        let mut world = World::new(WorldParams::default(), 0);
+        for x in -1..1 {
+            for y in -1..1 {
+                for z in -1..1 {
+                    world.load_chunk(Chunk::new(Pos::new(x, y, z), world.params().chunk_size));
                 }
             }
         }
-        Materials::Air.get_properties().color
+        world
+    }
+
+    #[test]
+    fn chunks_know_when_they_are_empty() {
+        let mut chunk = Chunk::new(Pos::new(0, 0, 0), 16);
+        assert!(chunk.is_empty());
+        let (a, b) = (Vec3::new(1.0, 2.0, 3.0), Vec3::new(2.0, 2.0, 3.0));
+        chunk.set_voxel(a, Materials::Stone as MaterialID);
+        chunk.set_voxel(b, Materials::Stone as MaterialID);
+        chunk.set_voxel(Vec3::new(15.0, 15.0, 15.0), Materials::Sand as MaterialID);
+        chunk.set_voxel(a, Materials::Stone as MaterialID);
+        chunk.set_voxel(a, Materials::Air as MaterialID);
+        assert!(!chunk.is_empty());
+        chunk.set_voxel(b, Materials::Air as MaterialID);
+        assert!(!chunk.is_empty());
+        chunk.set_voxel(Vec3::new(15.0, 15.0, 15.0), Materials::Air as MaterialID);
+        assert!(chunk.is_empty());
+        // clearing air again changes nothing
+        chunk.set_voxel(b, Materials::Air as MaterialID);
+        assert!(chunk.is_empty());
+    }
+
+    #[test]
+    fn split_pos_on_both_sides_of_zero() {
+        let world = World::new(WorldParams::default(), 0);
+        assert_eq!(world.split_pos(Pos::new(-1, 0, -8)), (Pos::new(-1, 0, -1), Pos::new(7, 0, 0)));
+        assert_eq!(world.split_pos(Pos::new(7, -9, 8)), (Pos::new(0, -2, 1), Pos::new(7, 7, 0)));
+    }
+
+    #[test]
+    fn voxels_round_trip_across_the_origin() {
+        let mut world = world_around_origin();
+        let positions = [Pos::new(-1, -1, -1), Pos::new(0, 0, 0), Pos::new(-8, 7, -1), Pos::new(7, -8, 0)];
+        for (i, &pos) in positions.iter().enumerate() {
+            assert!(world.set_voxel(pos, i as MaterialID + 1));
+        }
+        for (i, &pos) in positions.iter().enumerate() {
+            assert_eq!(world.voxel_at(pos), Some(i as MaterialID + 1));
+        }
+        // neighbours on the other side of a chunk border are untouched
+        assert_eq!(world.voxel_at(Pos::new(0, -1, -1)), Some(Materials::Air as MaterialID));
+        assert_eq!(world.voxel_at(Pos::new(-9, 0, 0)), None);
+    }
+
+    #[test]
+    fn exit_cell_steps_from_negative_into_positive() {
+        let ray = Ray::new(Vec3::new(-0.5, 0.5, -0.25), Vec3::new(1.0, 0.0, 0.0));
+        let (dist, next) = ray.exit_cell(Pos::new(-1, 0, -1), 1, 0.0);
+        assert!((dist - 0.5).abs() < 1e-4);
+        assert_eq!(next, Pos::new(0, 0, -1));
+
+        // a whole chunk at once
+        let ray = Ray::new(Vec3::new(-3.5, -0.5, 0.5), Vec3::new(0.0, 1.0, 0.0));
+        let (dist, next) = ray.exit_cell(Pos::new(-4, -1, 0), 8, 0.0);
+        assert!((dist - 0.5).abs() < 1e-4);
+        assert_eq!(next, Pos::new(-4, 0, 0));
     }
-    */
-}
\ No newline at end of file
+
+    #[test]
+    fn cast_hits_voxels_behind_the_origin() {
+        let mut world = world_around_origin();
+        world.set_voxel(Pos::new(2, -1, -3), Materials::Stone as MaterialID);
+        let ray = Ray::new(Vec3::new(-5.5, -0.5, -2.5), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(ray.cast(&world, 16.0), Some((Pos::new(2, -1, -3), Pos::new(1, -1, -3))));
+    }
+}
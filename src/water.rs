@@ -0,0 +1,160 @@
+use crate::linalg::*;
+use crate::voxels::{World, MaterialID, Materials};
+use crate::simulation::{TickSystem, TickContext};
+
+
+
+// Water as a cellular automaton. Every water voxel holds a fill level from 1 to FULL in its
+// metadata, generated water has no metadata and counts as full. Each tick a voxel pours as
+// much as fits into the voxel below, then hands single units to the sideways neighbours that
+// are at least two levels lower. Water is never created or destroyed, and the levels of a pool
+// end up at most one apart, at which point nothing moves anymore.
+// Only the chunks changed in the last tick and their neighbours are looked at, so still water
+// costs nothing; moving water changes its chunks and keeps them active
+pub const FULL: u8 = 8;
+
+const SIDEWAYS: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+
+
+pub struct WaterFlow;
+
+impl TickSystem for WaterFlow {
+    fn name(&self) -> &str {
+        "water"
+    }
+
+    fn tick(&mut self, world: &mut World, ctx: &TickContext) {
+        for pos in ctx.active_voxels(world, |material| material == Materials::Water as MaterialID) {
+            flow(world, pos);
+        }
+    }
+}
+
+
+fn flow(world: &mut World, pos: Pos) {
+    // the voxel may have drained earlier in this tick
+    let Some(mut level) = level_at(world, pos).filter(|&level| level > 0) else { return; };
+    let start = level;
+
+    let below = pos.sub(&Pos::new(0, 1, 0));
+    if let Some(under) = level_at(world, below).filter(|&under| under < FULL) {
+        let poured = level.min(FULL - under);
+        set_level(world, below, under + poured);
+        level -= poured;
+    }
+
+    for (dx, dz) in SIDEWAYS {
+        if level <= 1 {
+            break;
+        }
+        let side = pos.add(&Pos::new(dx, 0, dz));
+        if let Some(next) = level_at(world, side).filter(|&next| next + 1 < level) {
+            set_level(world, side, next + 1);
+            level -= 1;
+        }
+    }
+
+    if level != start {
+        set_level(world, pos, level);
+    }
+}
+
+// None where water can't go: solid voxels and unloaded chunks
+pub fn level_at(world: &World, pos: Pos) -> Option<u8> {
+    match world.voxel_at(pos)? {
+        m if m == Materials::Water as MaterialID => match world.metadata_at(pos)? {
+            0 => Some(FULL),
+            level => Some(level),
+        },
+        m if m == Materials::Air as MaterialID => Some(0),
+        _ => None,
+    }
+}
+
+pub fn set_level(world: &mut World, pos: Pos, level: u8) {
+    if level == 0 {
+        world.set_voxel(pos, Materials::Air as MaterialID);
+    }
+    else {
+        world.set_voxel(pos, Materials::Water as MaterialID);
+        world.set_metadata(pos, if level == FULL { 0 } else { level });
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::Simulation;
+    use crate::storage::RegionStore;
+    use crate::voxels::{Chunk, WorldParams};
+
+    // far above the terrain, so chunks that were never edited come back empty
+    const SKY: i32 = 100;
+
+    // a stone basin two chunks wide with a tower of partly filled water over one side
+    fn basin(name: &str) -> Box<World> {
+        let dir = std::env::temp_dir().join(format!("raytracer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut world = World::new(WorldParams::default(), 0);
+        world.attach_store(RegionStore::open(&dir, 8, 0).unwrap());
+        for x in -1..1 {
+            for y in SKY..SKY + 2 {
+                world.load_chunk(Chunk::new(Pos::new(x, y, 0), 8));
+            }
+        }
+        let floor = SKY * 8;
+        for x in -8..8 {
+            for z in 0..8 {
+                world.set_voxel(Pos::new(x, floor, z), Materials::Stone as MaterialID);
+            }
+        }
+        for y in 1..12 {
+            set_level(&mut world, Pos::new(-3, floor + y, 4), (y % FULL as i32) as u8 + 1);
+        }
+        world
+    }
+
+    fn total_water(world: &World) -> u32 {
+        let mut total = 0;
+        for x in -8..8 {
+            for y in SKY * 8..(SKY + 2) * 8 {
+                for z in 0..8 {
+                    total += level_at(world, Pos::new(x, y, z)).unwrap_or(0) as u32;
+                }
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn fill_levels_survive_unloading() {
+        let mut world = basin("water-unload");
+        let pos = Pos::new(-3, SKY * 8 + 2, 4);
+        set_level(&mut world, pos, 3);
+        let (chunk, _) = world.split_pos(pos);
+        world.unload_chunk(chunk);
+        world.ensure_loaded(chunk);
+        assert_eq!(level_at(&world, pos), Some(3));
+    }
+
+    #[test]
+    fn water_is_conserved_across_unloads() {
+        let mut world = basin("water-conserved");
+        let start = total_water(&world);
+        let mut simulation = Simulation::new(0);
+        simulation.register(WaterFlow);
+        for round in 0..20 {
+            for _ in 0..5 {
+                simulation.step(&mut world);
+                assert_eq!(total_water(&world), start, "round {}", round);
+            }
+            let loaded: Vec<Pos> = world.loaded_chunks().map(|chunk| chunk.coords()).collect();
+            for coords in loaded {
+                world.unload_chunk(coords);
+                world.ensure_loaded(coords);
+            }
+            assert_eq!(total_water(&world), start, "round {} after unloading", round);
+        }
+    }
+}